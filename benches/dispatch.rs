@@ -0,0 +1,158 @@
+//! Frames going through a `Proxy` with many handlers, most of them for other message types.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use demand_easy_sv2::{
+    const_sv2::{
+        MESSAGE_TYPE_CLOSE_CHANNEL, MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+        MESSAGE_TYPE_NEW_MINING_JOB, MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL,
+        MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL, MESSAGE_TYPE_SET_CUSTOM_MINING_JOB,
+        MESSAGE_TYPE_SET_EXTRANONCE_PREFIX, MESSAGE_TYPE_SET_TARGET,
+        MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+        MESSAGE_TYPE_UPDATE_CHANNEL,
+    },
+    roles_logic_sv2::{mining_sv2::SubmitSharesExtended, parsers::Mining},
+    Frame_, Handler, PoolMessages, ProxyBuilder, Remote, Reply, StdFrame,
+};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+const FRAMES: usize = 1000;
+
+const OTHER_MESSAGE_TYPES: [u8; 10] = [
+    MESSAGE_TYPE_CLOSE_CHANNEL,
+    MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+    MESSAGE_TYPE_NEW_MINING_JOB,
+    MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL,
+    MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL,
+    MESSAGE_TYPE_SET_CUSTOM_MINING_JOB,
+    MESSAGE_TYPE_SET_EXTRANONCE_PREFIX,
+    MESSAGE_TYPE_SET_TARGET,
+    MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+    MESSAGE_TYPE_UPDATE_CHANNEL,
+];
+
+/// A serialized `SubmitSharesExtended` frame, as received from the network.
+fn share() -> Vec<u8> {
+    let message = PoolMessages::Mining(Mining::SubmitSharesExtended(SubmitSharesExtended {
+        channel_id: 1,
+        sequence_number: 2,
+        job_id: 3,
+        nonce: 4,
+        ntime: 5,
+        version: 6,
+        extranonce: vec![7; 32].try_into().unwrap(),
+    }));
+    let frame: StdFrame = message.try_into().unwrap();
+    let mut bytes = vec![0; frame.encoded_length()];
+    frame.serialize(&mut bytes).unwrap();
+    bytes
+}
+
+fn frames(bytes: &[u8]) -> Vec<Frame_> {
+    (0..FRAMES)
+        .map(|_| {
+            let frame: StdFrame = StdFrame::from_bytes(bytes.to_vec().into()).unwrap();
+            frame.into()
+        })
+        .collect()
+}
+
+struct Shares;
+
+impl Handler for Shares {
+    async fn on_submit_shares_extended(&mut self, _: SubmitSharesExtended<'static>) -> Reply {
+        Reply::PassThrough
+    }
+}
+
+fn drain<T: Send + 'static>(mut receiver: Receiver<T>) {
+    tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+}
+
+/// A running proxy, frames sent to `to_proxy` are forwarded to `from_proxy`.
+struct RunningProxy {
+    to_proxy: Sender<Frame_>,
+    from_proxy: Receiver<Frame_>,
+    _to_proxy_from_server: Sender<Frame_>,
+    _from_proxy_to_client: Receiver<Frame_>,
+}
+
+fn start_proxy(runtime: &Runtime, add_handlers: impl FnOnce(&mut ProxyBuilder)) -> RunningProxy {
+    let _guard = runtime.enter();
+    let (to_proxy, from_client) = channel(64);
+    let (to_client, from_proxy_to_client) = channel(64);
+    let (to_proxy_from_server, from_server) = channel(64);
+    let (to_server, from_proxy) = channel(64);
+    let mut builder = ProxyBuilder::new();
+    builder.try_with_client(from_client, to_client).unwrap();
+    builder.try_with_server(from_server, to_server).unwrap();
+    add_handlers(&mut builder);
+    let proxy = builder.try_build().unwrap();
+    tokio::spawn(proxy.start());
+    RunningProxy {
+        to_proxy,
+        from_proxy,
+        _to_proxy_from_server: to_proxy_from_server,
+        _from_proxy_to_client: from_proxy_to_client,
+    }
+}
+
+fn bench_proxy(c: &mut Criterion, name: &str, add_handlers: impl FnOnce(&mut ProxyBuilder)) {
+    // A single thread makes the measures less noisy
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut proxy = start_proxy(&runtime, add_handlers);
+    let share = share();
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || frames(&share),
+            |frames| {
+                runtime.block_on(async {
+                    let send = async {
+                        for frame in frames {
+                            proxy.to_proxy.send(frame).await.unwrap();
+                        }
+                    };
+                    let recv = async {
+                        for _ in 0..FRAMES {
+                            proxy.from_proxy.recv().await.unwrap();
+                        }
+                    };
+                    tokio::join!(send, recv);
+                })
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn dispatch(c: &mut Criterion) {
+    bench_proxy(c, "no_handler", |_| ());
+    bench_proxy(c, "observers_of_other_types", |builder| {
+        for message_type in OTHER_MESSAGE_TYPES {
+            drain(builder.add_handler(Remote::Client, message_type));
+        }
+    });
+    bench_proxy(c, "observers_of_the_type", |builder| {
+        for _ in 0..10 {
+            drain(builder.add_handler(Remote::Client, MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED));
+        }
+    });
+    bench_proxy(c, "observers_and_message_handler", |builder| {
+        for message_type in OTHER_MESSAGE_TYPES {
+            drain(builder.add_handler(Remote::Client, message_type));
+        }
+        drain(builder.add_handler(Remote::Client, MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED));
+        builder.add_message_handler(Remote::Client, Shares).unwrap();
+    });
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,281 @@
+use binary_sv2::Str0255;
+use roles_logic_sv2::{
+    common_messages_sv2::SetupConnection,
+    mining_sv2::{OpenExtendedMiningChannel, OpenStandardMiningChannel},
+    parsers::{Mining, PoolMessages},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use crate::{message_channel::ConnectionId, request::u32_as_ref};
+
+/// What an `Authorizer` decides about a downstream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Authorization {
+    /// Let the downstream in.
+    Accept,
+    /// Let the downstream in and tag it with an identity: the whole connection for a
+    /// SetupConnection, only the opened channel for an open channel message. The identity comes
+    /// with the messages received by the inbound handlers and can be read with `Identities`.
+    AcceptAs(String),
+    /// Refuse with this SV2 error code, at most 255 bytes, longer codes are replaced by
+    /// `unauthorized`.
+    Reject(String),
+}
+
+/// A downstream being authorized.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub connection: ConnectionId,
+    /// `None` when the downstream is not connected with TCP
+    pub address: Option<SocketAddr>,
+    /// The identity given to the SetupConnection by `Authorization::AcceptAs`
+    pub identity: Option<String>,
+}
+
+/// Decide which downstreams can connect to a `Server` and open channels. Every method defaults
+/// to `Authorization::Accept`:
+///
+/// ```ignore
+/// struct Vendors;
+///
+/// impl Authorizer for Vendors {
+///     async fn on_setup_connection(
+///         &self,
+///         _peer: Peer,
+///         message: SetupConnection<'static>,
+///     ) -> Authorization {
+///         match message.vendor.to_vec().as_slice() {
+///             b"bitaxe" => Authorization::Accept,
+///             _ => Authorization::Reject("unknown-vendor".to_string()),
+///         }
+///     }
+/// }
+/// ```
+///
+/// A rejected SetupConnection is answered with `SetupConnection.Error` and the downstream is
+/// disconnected, a rejected channel is answered with `OpenMiningChannel.Error` and the open
+/// channel message is not dispatched to the handlers.
+pub trait Authorizer: Send + Sync + 'static {
+    /// Called with the SetupConnection of every downstream, after it has been checked against
+    /// the protocol, versions and flags supported by the server.
+    fn on_setup_connection(
+        &self,
+        peer: Peer,
+        message: SetupConnection<'static>,
+    ) -> impl Future<Output = Authorization> + Send {
+        let _ = (peer, message);
+        async { Authorization::Accept }
+    }
+    /// Called for every `OpenStandardMiningChannel` received.
+    fn on_open_standard_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenStandardMiningChannel<'static>,
+    ) -> impl Future<Output = Authorization> + Send {
+        let _ = (peer, message);
+        async { Authorization::Accept }
+    }
+    /// Called for every `OpenExtendedMiningChannel` received.
+    fn on_open_extended_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenExtendedMiningChannel<'static>,
+    ) -> impl Future<Output = Authorization> + Send {
+        let _ = (peer, message);
+        async { Authorization::Accept }
+    }
+}
+
+type AuthorizationFuture<'a> = Pin<Box<dyn Future<Output = Authorization> + Send + 'a>>;
+
+/// Object safe version of `Authorizer`.
+pub(crate) trait DynAuthorizer: Send + Sync {
+    fn setup_connection(
+        &self,
+        peer: Peer,
+        message: SetupConnection<'static>,
+    ) -> AuthorizationFuture<'_>;
+    fn open_standard_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenStandardMiningChannel<'static>,
+    ) -> AuthorizationFuture<'_>;
+    fn open_extended_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenExtendedMiningChannel<'static>,
+    ) -> AuthorizationFuture<'_>;
+}
+
+impl<A: Authorizer> DynAuthorizer for A {
+    fn setup_connection(
+        &self,
+        peer: Peer,
+        message: SetupConnection<'static>,
+    ) -> AuthorizationFuture<'_> {
+        Box::pin(self.on_setup_connection(peer, message))
+    }
+    fn open_standard_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenStandardMiningChannel<'static>,
+    ) -> AuthorizationFuture<'_> {
+        Box::pin(self.on_open_standard_mining_channel(peer, message))
+    }
+    fn open_extended_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenExtendedMiningChannel<'static>,
+    ) -> AuthorizationFuture<'_> {
+        Box::pin(self.on_open_extended_mining_channel(peer, message))
+    }
+}
+
+/// The identities given to the downstreams by `Authorization::AcceptAs`: the one given to the
+/// SetupConnection applies to the whole connection and the one given to an open channel message
+/// only to the channel it opens. Inbound handlers receive the identity with every message, the
+/// identities are removed when the downstream disconnects.
+#[derive(Clone, Default)]
+pub struct Identities(Arc<StdMutex<HashMap<ConnectionId, ConnectionIdentities>>>);
+
+#[derive(Default)]
+struct ConnectionIdentities {
+    /// Given to the SetupConnection
+    connection: Option<String>,
+    /// Given to the open channel messages, by request id until the channel is opened
+    requests: HashMap<u32, String>,
+    /// By channel id
+    channels: HashMap<u32, String>,
+}
+
+impl ConnectionIdentities {
+    fn request(&self, request_id: u32) -> Option<String> {
+        self.requests
+            .get(&request_id)
+            .or(self.connection.as_ref())
+            .cloned()
+    }
+
+    fn channel(&self, channel_id: u32) -> Option<String> {
+        self.channels
+            .get(&channel_id)
+            .or(self.connection.as_ref())
+            .cloned()
+    }
+}
+
+impl Identities {
+    /// The identity given to the SetupConnection of the downstream.
+    pub fn get(&self, connection: ConnectionId) -> Option<String> {
+        self.0
+            .lock()
+            .expect("Identities mutex poisoned")
+            .get(&connection)
+            .and_then(|identities| identities.connection.clone())
+    }
+
+    /// The identity given to the open channel message of the channel, or else to the
+    /// SetupConnection of the downstream.
+    pub fn get_channel(&self, connection: ConnectionId, channel_id: u32) -> Option<String> {
+        self.0
+            .lock()
+            .expect("Identities mutex poisoned")
+            .get(&connection)
+            .and_then(|identities| identities.channel(channel_id))
+    }
+
+    pub(crate) fn set(&self, connection: ConnectionId, identity: String) {
+        self.0
+            .lock()
+            .expect("Identities mutex poisoned")
+            .entry(connection)
+            .or_default()
+            .connection = Some(identity);
+    }
+
+    /// Set the identity of the channel that the request is opening, until the server answers.
+    pub(crate) fn set_request(&self, connection: ConnectionId, request_id: u32, identity: String) {
+        self.0
+            .lock()
+            .expect("Identities mutex poisoned")
+            .entry(connection)
+            .or_default()
+            .requests
+            .insert(request_id, identity);
+    }
+
+    /// Give to the channel opened by `message`, if it is the answer to an open channel message,
+    /// the identity of the request.
+    pub(crate) fn on_sent(&self, connection: ConnectionId, message: &PoolMessages<'static>) {
+        let (request_id, channel_id) = match message {
+            PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(m)) => {
+                (u32_as_ref(&m.request_id), Some(m.channel_id))
+            }
+            PoolMessages::Mining(Mining::OpenExtendedMiningChannelSuccess(m)) => {
+                (m.request_id, Some(m.channel_id))
+            }
+            PoolMessages::Mining(Mining::OpenMiningChannelError(m)) => (m.request_id, None),
+            _ => return,
+        };
+        let mut identities = self.0.lock().expect("Identities mutex poisoned");
+        let Some(identities) = identities.get_mut(&connection) else {
+            return;
+        };
+        let identity = identities.requests.remove(&request_id);
+        if let Some(channel_id) = channel_id {
+            // A channel id can be reused after the channel is closed
+            match identity {
+                Some(identity) => identities.channels.insert(channel_id, identity),
+                None => identities.channels.remove(&channel_id),
+            };
+        }
+    }
+
+    /// The identity of a message received from the downstream.
+    pub(crate) fn of_message(
+        &self,
+        connection: ConnectionId,
+        message: &PoolMessages<'static>,
+    ) -> Option<String> {
+        let identities = self.0.lock().expect("Identities mutex poisoned");
+        let identities = identities.get(&connection)?;
+        let channel_id = match message {
+            PoolMessages::Mining(Mining::OpenStandardMiningChannel(m)) => {
+                return identities.request(u32_as_ref(&m.request_id));
+            }
+            PoolMessages::Mining(Mining::OpenExtendedMiningChannel(m)) => {
+                return identities.request(m.request_id);
+            }
+            PoolMessages::Mining(Mining::SubmitSharesStandard(m)) => m.channel_id,
+            PoolMessages::Mining(Mining::SubmitSharesExtended(m)) => m.channel_id,
+            PoolMessages::Mining(Mining::UpdateChannel(m)) => m.channel_id,
+            PoolMessages::Mining(Mining::CloseChannel(m)) => m.channel_id,
+            PoolMessages::Mining(Mining::SetCustomMiningJob(m)) => m.channel_id,
+            _ => return identities.connection.clone(),
+        };
+        identities.channel(channel_id)
+    }
+
+    pub(crate) fn remove(&self, connection: ConnectionId) {
+        self.0
+            .lock()
+            .expect("Identities mutex poisoned")
+            .remove(&connection);
+    }
+}
+
+/// The error code sent to a rejected downstream.
+pub(crate) fn error_code(code: String) -> Str0255<'static> {
+    code.try_into().unwrap_or_else(|_| {
+        "unauthorized"
+            .to_string()
+            .try_into()
+            .expect("Error codes are shorter than 255 bytes")
+    })
+}
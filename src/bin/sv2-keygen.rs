@@ -0,0 +1,19 @@
+//! Generate a new authority key pair.
+//!
+//! `sv2-keygen` prints the key pair, `sv2-keygen <path>` saves it in a new key file readable only
+//! by its owner and prints the public key.
+use demand_easy_sv2::keys::AuthorityKeyPair;
+
+fn main() {
+    let key_pair = AuthorityKeyPair::generate();
+    match std::env::args().nth(1) {
+        Some(path) => {
+            if let Err(e) = key_pair.write(&path) {
+                eprintln!("Impossible to write key file {path}: {e:?}");
+                std::process::exit(1);
+            }
+            println!("public_key = {}", key_pair.public_key);
+        }
+        None => print!("{key_pair}"),
+    }
+}
@@ -1,65 +1,376 @@
 use codec_sv2::{HandshakeRole, Initiator};
 pub use const_sv2;
-use demand_sv2_connection::noise_connection_tokio::Connection;
 use key_utils::{Error as KeyUtilsError, Secp256k1PublicKey};
 pub use roles_logic_sv2;
 pub use roles_logic_sv2::parsers::PoolMessages;
 use roles_logic_sv2::{
-    common_messages_sv2::{Protocol, SetupConnection},
+    common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
     parsers::CommonMessages,
 };
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::{
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
     select,
     sync::mpsc::{channel, Receiver, Sender},
+    task::AbortHandle,
+    time::timeout,
 };
 
 use crate::Frame_;
 use crate::Remote;
 use crate::StdFrame;
-use crate::{into_static, message_channel::MessageChannel};
+use crate::{
+    connection::{
+        noise_connection, peer_name, plain_connection, recv_within, Connection, Timeouts,
+    },
+    extension::{
+        decode_frame, ExtensionMessage, ExtensionRegistry, RawChannel, RawFrame, RawHandler,
+        RequestExtensions, RequestExtensionsError, RequestExtensionsSuccess,
+    },
+    handler::{
+        push_handler, AnyHandler, Dispatcher, Handler, MessageHandler, OwnerConflict, TypedMessage,
+    },
+    inbound::{Inbound, InboundSender, Origins},
+    into_static,
+    message_channel::{
+        into_frame, message_from_frame, recv_or_pending, DropCounter, FrameError, HandlerConfig,
+        HandlerKey, HandlerReceiver, HandlerSender, Interceptor, InvalidFrame, InvalidFramePolicy,
+        InvalidFrames, MessageChannel, MessageChannelError, MessageType, Observer, Outcome,
+        Overflow, OverflowPolicy, Typed,
+    },
+    request::{PendingRequests, Request},
+    shutdown::ShutdownSignal,
+    Requester, ShutdownHandle,
+};
 
 pub struct Client {
-    from_server: Receiver<Frame_>,
-    to_server: Sender<Frame_>,
-    handlers: Vec<MessageChannel>,
+    connection: Option<(Receiver<Frame_>, Sender<Frame_>)>,
+    upstreams: Vec<Upstream>,
+    backoff: Backoff,
+    timeouts: Timeouts,
+    handlers: Dispatcher,
     messages_to_send: Option<Receiver<PoolMessages<'static>>>,
+    interceptors: Vec<Interceptor>,
     setup_connection_message: Option<PoolMessages<'static>>,
     protocol: Protocol,
+    invalid_frames: InvalidFrames,
+    setup_connection_handler: Option<Observer<SetupConnectionSuccess>>,
+    connection_state_handler: Option<Observer<ConnectionState>>,
+    extensions: Extensions,
+    extensions_to_send: Option<Receiver<RawFrame>>,
+    requests: Option<Receiver<Request>>,
+    pending_requests: PendingRequests,
+    shutdown: ShutdownSignal,
+    origins: Origins,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// Why a `Client` stopped. Unlike `ServerError` and `ProxyError` it is not `Copy`: the rejection
+/// variants carry what the upstream sent, clone the error to keep a copy of it.
+#[derive(Clone, Debug, PartialEq)]
 pub enum ClientError {
     MessagesToSendSenderDropped,
     UpstreamClosed,
     UpstreamClosedDuringSetupSv2Connection,
     ImpossibleSetupSv2ConnectionWithUpstream,
+    /// The upstream answered the SetupConnection with a SetupConnection.Error
+    SetupConnectionRejected {
+        error_code: String,
+        flags: u32,
+    },
+    /// The upstream does not support extensions required by the client, or requires extensions
+    /// that the client does not support
+    ExtensionsRejected {
+        unsupported: Vec<u16>,
+        required: Vec<u16>,
+    },
+    InvalidFrame(FrameError),
+    HandlerDropped(MessageType),
+    /// The channel of a handler with `OverflowPolicy::Disconnect` is full
+    HandlerOverflow(MessageType),
+    InterceptorDropped,
+    /// A frame from the extension sender has a payload of 2^24 bytes or more
+    PayloadTooLong(MessageType),
+    /// The upstream did not complete the SetupConnection in time
+    SetupTimeout,
+    /// Nothing has been received from the upstream for longer than the idle timeout
+    IdleTimeout,
+}
+
+impl ClientError {
+    /// Errors caused by the upstream, after them the client can connect again to an upstream.
+    /// A full handler channel with `OverflowPolicy::Disconnect` also disconnects the upstream.
+    fn is_upstream_error(&self) -> bool {
+        matches!(
+            self,
+            Self::UpstreamClosed
+                | Self::UpstreamClosedDuringSetupSv2Connection
+                | Self::ImpossibleSetupSv2ConnectionWithUpstream
+                | Self::SetupConnectionRejected { .. }
+                | Self::ExtensionsRejected { .. }
+                | Self::InvalidFrame(_)
+                | Self::HandlerOverflow(_)
+                | Self::SetupTimeout
+                | Self::IdleTimeout
+        )
+    }
+}
+
+/// Events sent to the connection state handler, upstreams are identified by their address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The Noise handshake and the SetupConnection with the upstream succeeded.
+    Connected(String),
+    /// The connection with the upstream has been lost, the client will try to connect again.
+    Disconnected(String, ClientError),
+    /// The client is now connected to a different upstream than the one it has been
+    /// disconnected from.
+    FailedOver { from: String, to: String },
+}
+
+enum Transport {
+    /// Noise encrypted, with the upstream authority public key if any
+    Noise(Option<Secp256k1PublicKey>),
+    /// Plain SV2 frames, see `ClientBuilder::add_upstream_unencrypted`
+    Unencrypted,
+}
+
+struct Upstream {
+    address: String,
+    transport: Transport,
+}
+
+impl Upstream {
+    /// Connect and complete the handshake, returns the connection and the address of the
+    /// upstream.
+    async fn connect(
+        &self,
+        handshake_timeout: Duration,
+    ) -> Option<(Connection, Option<SocketAddr>)> {
+        match timeout(handshake_timeout, self.try_connect()).await {
+            Ok(connection) => connection,
+            Err(_) => {
+                eprintln!("Handshake with upstream {} timed out", self.address);
+                None
+            }
+        }
+    }
+
+    async fn try_connect(&self) -> Option<(Connection, Option<SocketAddr>)> {
+        let stream = match TcpStream::connect(&self.address).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Impossible to connect to upstream {}: {e}", self.address);
+                return None;
+            }
+        };
+        let address = stream.peer_addr().ok();
+        let auth_key = match self.transport {
+            Transport::Noise(auth_key) => auth_key,
+            Transport::Unencrypted => {
+                return Some((plain_connection(stream, &self.address), address))
+            }
+        };
+        noise_connection(stream, &self.address, initiator(auth_key))
+            .await
+            .map(|connection| (connection, address))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+fn initiator(auth_key: Option<Secp256k1PublicKey>) -> HandshakeRole {
+    let initiator = match auth_key {
+        Some(key) => Initiator::from_raw_k(key.into_bytes())
+            .expect("Pub key is already checked for validity"),
+        None => Initiator::without_pk().expect("This fn call can not fail"),
+    };
+    HandshakeRole::Initiator(initiator)
 }
 
 impl Client {
     pub async fn start(mut self) -> Result<(), ClientError> {
-        let mut client_handlers = vec![];
-        let mut server_handlers = vec![];
-        self.setup_connection().await?;
-        for handler in self.handlers {
-            match handler.expect_from {
-                Remote::Client => client_handlers.push(handler),
-                Remote::Server => server_handlers.push(handler),
+        match self.connection.take() {
+            Some((mut from_server, to_server)) => {
+                self.setup_connection(&mut from_server, &to_server).await?;
+                self.run(from_server, to_server).await
             }
+            None => self.start_with_failover().await,
         }
-        if let Some(messages_to_send) = self.messages_to_send {
-            select! {
-                r = Self::send_to_up(messages_to_send, self.to_server.clone()) => r,
-                r = Self::recv_from_up(self.from_server, self.to_server, server_handlers) => r,
+    }
+
+    /// Connect to the first upstream that completes the handshake and the SetupConnection, in
+    /// the order they have been added. When the connection is lost, or when no upstream is
+    /// available, try again after an exponential backoff.
+    async fn start_with_failover(mut self) -> Result<(), ClientError> {
+        let upstreams = std::mem::take(&mut self.upstreams);
+        // `connect_to_upstream` borrows the client mutably, the shutdown is awaited on a clone
+        let shutdown = self.shutdown.clone();
+        let mut current: Option<usize> = None;
+        let mut delay = None;
+        loop {
+            if let Some(delay) = delay {
+                select! {
+                    _ = tokio::time::sleep(delay) => (),
+                    _ = shutdown.requested() => return Ok(()),
+                }
             }
-        } else {
-            Self::recv_from_up(self.from_server, self.to_server, server_handlers).await
+            // An upstream that does not answer would delay the shutdown until the handshake and
+            // the setup timeouts
+            let connected = select! {
+                connected = self.connect_to_upstream(&upstreams) => connected,
+                _ = shutdown.requested() => return Ok(()),
+            };
+            let Some((index, from_server, to_server, handles)) = connected else {
+                eprintln!("Impossible to connect to any upstream");
+                delay = Some(match delay {
+                    Some(delay) => (delay * 2).min(self.backoff.max),
+                    None => self.backoff.initial,
+                });
+                continue;
+            };
+            delay = Some(self.backoff.initial);
+            let address = &upstreams[index].address;
+            if let Some(previous) = current.filter(|previous| *previous != index) {
+                self.send_connection_state(ConnectionState::FailedOver {
+                    from: upstreams[previous].address.clone(),
+                    to: address.clone(),
+                })
+                .await;
+            }
+            current = Some(index);
+            self.send_connection_state(ConnectionState::Connected(address.clone()))
+                .await;
+
+            let result = self.run(from_server, to_server).await;
+            // After a shutdown the noise connection is left to flush the last frames
+            if result.is_err() {
+                for handle in handles {
+                    handle.abort();
+                }
+            }
+            self.pending_requests.remove_connection(0);
+            match result {
+                Err(e)
+                    if e.is_upstream_error()
+                        && self.invalid_frames.policy != InvalidFramePolicy::Abort =>
+                {
+                    eprintln!("Disconnected from upstream {address}: {e:?}");
+                    self.send_connection_state(ConnectionState::Disconnected(address.clone(), e))
+                        .await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn connect_to_upstream(
+        &mut self,
+        upstreams: &[Upstream],
+    ) -> Option<(usize, Receiver<Frame_>, Sender<Frame_>, [AbortHandle; 2])> {
+        for (index, upstream) in upstreams.iter().enumerate() {
+            if let Some(((mut from_server, to_server, handles), address)) =
+                upstream.connect(self.timeouts.handshake).await
+            {
+                self.origins.set_address(0, address);
+                match self.setup_connection(&mut from_server, &to_server).await {
+                    Ok(()) => return Some((index, from_server, to_server, handles)),
+                    Err(e) => {
+                        eprintln!(
+                            "Impossible to setup connection with {}: {e:?}",
+                            upstream.address
+                        );
+                        for handle in handles {
+                            handle.abort();
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    async fn send_connection_state(&self, state: ConnectionState) {
+        if let Some(handler) = &self.connection_state_handler {
+            handler.send(state, "connection state").await;
         }
     }
 
-    async fn setup_connection(&mut self) -> Result<(), ClientError> {
-        let recv = &mut self.from_server;
-        let send = &self.to_server;
+    /// Exchange messages with the upstream until the connection is closed or the shutdown is
+    /// requested.
+    async fn run(
+        &mut self,
+        mut from_server: Receiver<Frame_>,
+        to_server: Sender<Frame_>,
+    ) -> Result<(), ClientError> {
+        tokio::try_join!(
+            Self::send_to_up(
+                &mut self.messages_to_send,
+                &mut self.requests,
+                &mut self.extensions_to_send,
+                &self.pending_requests,
+                &to_server,
+                &mut self.interceptors,
+                &self.shutdown,
+            ),
+            Self::recv_from_up(
+                &mut from_server,
+                &to_server,
+                &mut self.handlers,
+                self.protocol,
+                &self.invalid_frames,
+                &self.pending_requests,
+                &mut self.extensions,
+                self.timeouts.idle,
+                &self.shutdown,
+            ),
+        )?;
+        for message in self.shutdown.goodbye(Remote::Server) {
+            let frame: StdFrame = message
+                .try_into()
+                .expect("A message can always be converted in a frame");
+            if to_server.send(frame.into()).await.is_err() {
+                return Err(ClientError::UpstreamClosed);
+            }
+        }
+        Ok(())
+    }
+
+    async fn setup_connection(
+        &mut self,
+        recv: &mut Receiver<Frame_>,
+        send: &Sender<Frame_>,
+    ) -> Result<(), ClientError> {
+        timeout(
+            self.timeouts.setup,
+            self.exchange_setup_connection(recv, send),
+        )
+        .await
+        .map_err(|_| ClientError::SetupTimeout)?
+    }
+
+    async fn exchange_setup_connection(
+        &mut self,
+        recv: &mut Receiver<Frame_>,
+        send: &Sender<Frame_>,
+    ) -> Result<(), ClientError> {
         let setup_connection = self.setup_connection_message.clone();
         let protocol = self.protocol;
         let setup_connection = match setup_connection {
@@ -78,62 +389,362 @@ impl Client {
             })),
         };
         let frame: StdFrame = setup_connection
+            .clone()
             .try_into()
             .expect("A message can always be converted in a frame");
         if send.send(frame.into()).await.is_err() {
-            Err(ClientError::UpstreamClosedDuringSetupSv2Connection)
-        } else {
-            if let Some(_) = recv.recv().await {
-                // TODO handle setup connection error here
+            return Err(ClientError::UpstreamClosedDuringSetupSv2Connection);
+        }
+        let mut frame = recv
+            .recv()
+            .await
+            .ok_or(ClientError::ImpossibleSetupSv2ConnectionWithUpstream)?;
+        match message_from_frame(&mut frame, Remote::Server, Some(self.protocol))? {
+            (_, PoolMessages::Common(CommonMessages::SetupConnectionSuccess(success))) => {
                 println!("Connection setup with upstream");
+                if let PoolMessages::Common(CommonMessages::SetupConnection(m)) = setup_connection {
+                    self.origins.set_setup(0, m);
+                }
+                self.origins.set_success(0, success);
+                if let Some(handler) = &self.setup_connection_handler {
+                    handler.send(success, "SetupConnection.Success").await;
+                }
+                if self.extensions.registry.is_empty() {
+                    return Ok(());
+                }
+                self.negotiate_extensions(recv, send).await
+            }
+            (_, PoolMessages::Common(CommonMessages::SetupConnectionError(error))) => {
+                let error_code = String::from_utf8_lossy(&error.error_code.to_vec()).to_string();
+                eprintln!("Upstream rejected SetupConnection: {error_code}");
+                Err(ClientError::SetupConnectionRejected {
+                    error_code,
+                    flags: error.flags,
+                })
+            }
+            (mt, _) => {
+                eprintln!(
+                    "Expected SetupConnection.Success or Error from upstream, received: {mt}"
+                );
+                Err(ClientError::ImpossibleSetupSv2ConnectionWithUpstream)
+            }
+        }
+    }
+
+    /// Request the registered extensions to the upstream, the extensions negotiated are sent to
+    /// the extensions handler. The client only waits for the answer when some extensions are
+    /// required, otherwise it is handled with the other frames by `on_frame`.
+    async fn negotiate_extensions(
+        &mut self,
+        recv: &mut Receiver<Frame_>,
+        send: &Sender<Frame_>,
+    ) -> Result<(), ClientError> {
+        let request = RequestExtensions {
+            request_id: 0,
+            requested_extensions: self.extensions.registry.supported.clone(),
+        };
+        if send
+            .send(
+                RawFrame::from_message(&request)
+                    .into_frame()
+                    .expect("Negotiation messages are shorter than a frame")
+                    .into(),
+            )
+            .await
+            .is_err()
+        {
+            return Err(ClientError::UpstreamClosedDuringSetupSv2Connection);
+        }
+        if self.extensions.registry.required.is_empty() {
+            self.extensions.awaiting_answer = true;
+            return Ok(());
+        }
+        let mut frame = recv
+            .recv()
+            .await
+            .ok_or(ClientError::ImpossibleSetupSv2ConnectionWithUpstream)?;
+        match self.extensions.answer(&mut frame)? {
+            Some(negotiated) => {
+                self.extensions.send_negotiated(negotiated).await;
                 Ok(())
-            } else {
+            }
+            None => {
+                eprintln!("Expected RequestExtensions.Success or Error from upstream");
                 Err(ClientError::ImpossibleSetupSv2ConnectionWithUpstream)
             }
         }
     }
 
     async fn send_to_up(
-        mut recv: Receiver<PoolMessages<'static>>,
-        send: Sender<Frame_>,
+        recv: &mut Option<Receiver<PoolMessages<'static>>>,
+        requests: &mut Option<Receiver<Request>>,
+        extensions: &mut Option<Receiver<RawFrame>>,
+        pending_requests: &PendingRequests,
+        send: &Sender<Frame_>,
+        interceptors: &mut [Interceptor],
+        shutdown: &ShutdownSignal,
     ) -> Result<(), ClientError> {
-        while let Some(message) = recv.recv().await {
-            let frame: StdFrame = message
-                .try_into()
-                .expect("A message can always be converted in a frame");
-            if send.send(frame.into()).await.is_err() {
-                return Err(ClientError::UpstreamClosed);
-            }
+        loop {
+            let message = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Flush the messages already queued
+                    while let Some(Ok(message)) = recv.as_mut().map(Receiver::try_recv) {
+                        Self::send_message(message, send, interceptors).await?;
+                    }
+                    while let Some(Ok(frame)) = extensions.as_mut().map(Receiver::try_recv) {
+                        Self::send_extension(frame, send).await?;
+                    }
+                    return Ok(());
+                }
+                message = recv_or_pending(recv) => {
+                    message.ok_or(ClientError::MessagesToSendSenderDropped)?
+                }
+                request = recv_or_pending(requests) => match request {
+                    Some(request) => pending_requests.register(request).1,
+                    None => {
+                        // Every requester has been dropped
+                        *requests = None;
+                        continue;
+                    }
+                },
+                frame = recv_or_pending(extensions) => {
+                    let frame = frame.ok_or(ClientError::MessagesToSendSenderDropped)?;
+                    Self::send_extension(frame, send).await?;
+                    continue;
+                }
+            };
+            Self::send_message(message, send, interceptors).await?;
+        }
+    }
+
+    async fn send_extension(frame: RawFrame, send: &Sender<Frame_>) -> Result<(), ClientError> {
+        let message_type = frame.message_type;
+        let frame = frame
+            .into_frame()
+            .ok_or(ClientError::PayloadTooLong(message_type))?;
+        if send.send(frame.into()).await.is_err() {
+            return Err(ClientError::UpstreamClosed);
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        message: PoolMessages<'static>,
+        send: &Sender<Frame_>,
+        interceptors: &mut [Interceptor],
+    ) -> Result<(), ClientError> {
+        let message = Interceptor::intercept(interceptors, message)
+            .await
+            .ok_or(ClientError::InterceptorDropped)?;
+        let frame: StdFrame = message
+            .try_into()
+            .expect("A message can always be converted in a frame");
+        if send.send(frame.into()).await.is_err() {
+            return Err(ClientError::UpstreamClosed);
         }
-        Err(ClientError::MessagesToSendSenderDropped)
+        Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn recv_from_up(
-        mut recv: Receiver<Frame_>,
-        send: Sender<Frame_>,
-        mut handlers: Vec<MessageChannel>,
+        recv: &mut Receiver<Frame_>,
+        send: &Sender<Frame_>,
+        handlers: &mut Dispatcher,
+        protocol: Protocol,
+        invalid_frames: &InvalidFrames,
+        pending_requests: &PendingRequests,
+        extensions: &mut Extensions,
+        idle: Option<Duration>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<(), ClientError> {
+        loop {
+            let frame = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Dispatch the frames already received
+                    while let Ok(frame) = recv.try_recv() {
+                        Self::on_frame(frame, SystemTime::now(), send, handlers, protocol, invalid_frames, pending_requests, extensions).await?;
+                    }
+                    return Ok(());
+                }
+                frame = recv_within(recv, idle) => frame
+                    .map_err(|_| ClientError::IdleTimeout)?
+                    .ok_or(ClientError::UpstreamClosed)?,
+            };
+            Self::on_frame(
+                frame,
+                SystemTime::now(),
+                send,
+                handlers,
+                protocol,
+                invalid_frames,
+                pending_requests,
+                extensions,
+            )
+            .await?;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn on_frame(
+        mut frame: Frame_,
+        received_at: SystemTime,
+        send: &Sender<Frame_>,
+        handlers: &mut Dispatcher,
+        protocol: Protocol,
+        invalid_frames: &InvalidFrames,
+        pending_requests: &PendingRequests,
+        extensions: &mut Extensions,
     ) -> Result<(), ClientError> {
-        while let Some(mut frame) = recv.recv().await {
-            for handler in handlers.iter_mut() {
-                if let Some(frame) = handler.on_message(&mut frame).await {
-                    if send.send(frame).await.is_err() {
-                        return Err(ClientError::UpstreamClosed);
-                    };
+        if extensions.on_frame(&mut frame).await? {
+            return Ok(());
+        }
+        let mut dispatch = handlers.dispatch(0, received_at, Some(protocol), &mut frame);
+        if !pending_requests.is_empty() {
+            match dispatch.take_message() {
+                Ok(Some(message)) => match pending_requests.on_response(0, message) {
+                    Some(message) => dispatch.put_message(message),
+                    None => return Ok(()),
+                },
+                Ok(None) => (),
+                Err(e) => return Self::on_invalid_frame(e, invalid_frames).await,
+            }
+        }
+        while let Some(outcome) = dispatch.next().await {
+            match outcome {
+                Ok(Outcome::Replies(replies)) => {
+                    for (_, message) in replies {
+                        if send.send(into_frame(message)).await.is_err() {
+                            return Err(ClientError::UpstreamClosed);
+                        };
+                    }
                 }
+                Ok(_) => (),
+                Err(MessageChannelError::InvalidFrame(e)) => {
+                    return Self::on_invalid_frame(e, invalid_frames).await
+                }
+                Err(e) => return Err(e.into()),
             }
         }
-        Err(ClientError::UpstreamClosed)
+        Ok(())
+    }
+
+    async fn on_invalid_frame(
+        error: FrameError,
+        invalid_frames: &InvalidFrames,
+    ) -> Result<(), ClientError> {
+        match invalid_frames
+            .on_invalid_frame(0, Remote::Server, error)
+            .await
+        {
+            InvalidFramePolicy::DropFrame => Ok(()),
+            _ => Err(ClientError::InvalidFrame(error)),
+        }
+    }
+}
+
+/// The extensions of a client and the state of their negotiation with the upstream.
+struct Extensions {
+    registry: ExtensionRegistry,
+    handler: Option<Observer<Vec<u16>>>,
+    /// The extensions have been requested without waiting for the answer, an upstream that does
+    /// not answer negotiates no extension
+    awaiting_answer: bool,
+}
+
+impl Extensions {
+    /// The extensions negotiated when `frame` is the answer to the `RequestExtensions`, `None` if
+    /// it is another frame.
+    fn answer(&self, frame: &mut Frame_) -> Result<Option<Vec<u16>>, ClientError> {
+        let negotiated = if let Some(success) = decode_frame::<RequestExtensionsSuccess>(frame)? {
+            success.supported_extensions
+        } else if let Some(error) = decode_frame::<RequestExtensionsError>(frame)? {
+            if !error.required_extensions.is_empty() {
+                eprintln!(
+                    "Upstream requires unsupported extensions: {:?}",
+                    error.required_extensions
+                );
+                return Err(ClientError::ExtensionsRejected {
+                    unsupported: error.unsupported_extensions,
+                    required: error.required_extensions,
+                });
+            }
+            self.registry
+                .supported
+                .iter()
+                .filter(|extension| !error.unsupported_extensions.contains(extension))
+                .copied()
+                .collect()
+        } else {
+            return Ok(None);
+        };
+        let unsupported: Vec<u16> = self
+            .registry
+            .required
+            .iter()
+            .filter(|extension| !negotiated.contains(extension))
+            .copied()
+            .collect();
+        if !unsupported.is_empty() {
+            eprintln!("Upstream does not support required extensions: {unsupported:?}");
+            return Err(ClientError::ExtensionsRejected {
+                unsupported,
+                required: vec![],
+            });
+        }
+        Ok(Some(negotiated))
+    }
+
+    /// Handle `frame` if it is the answer the client is waiting for, `true` if it was.
+    async fn on_frame(&mut self, frame: &mut Frame_) -> Result<bool, ClientError> {
+        if !self.awaiting_answer {
+            return Ok(false);
+        }
+        let Some(negotiated) = self.answer(frame)? else {
+            return Ok(false);
+        };
+        self.awaiting_answer = false;
+        self.send_negotiated(negotiated).await;
+        Ok(true)
+    }
+
+    async fn send_negotiated(&self, negotiated: Vec<u16>) {
+        if let Some(handler) = &self.handler {
+            handler.send(negotiated, "negotiated extensions").await;
+        }
     }
 }
 
+/// The connection state and SetupConnection handlers drop what does not fit in their channel,
+/// waiting for them would delay the connections.
+const NEVER_WAIT: HandlerConfig = HandlerConfig {
+    capacity: 3,
+    overflow: OverflowPolicy::DropNewest,
+};
+
 pub struct ClientBuilder {
     from_server: Option<Receiver<Frame_>>,
     to_server: Option<Sender<Frame_>>,
     server_auth_key: Option<Secp256k1PublicKey>,
-    handlers: Vec<MessageChannel>,
+    upstreams: Vec<Upstream>,
+    backoff: Backoff,
+    timeouts: Timeouts,
+    handlers: Vec<AnyHandler>,
     messages_to_send: Option<Receiver<PoolMessages<'static>>>,
+    interceptors: Vec<Interceptor>,
     setup_connection_message: Option<PoolMessages<'static>>,
     protocol: Option<Protocol>,
+    invalid_frame_policy: InvalidFramePolicy,
+    invalid_frame_handler: Option<Observer<InvalidFrame>>,
+    setup_connection_handler: Option<Observer<SetupConnectionSuccess>>,
+    connection_state_handler: Option<Observer<ConnectionState>>,
+    extensions: ExtensionRegistry,
+    extensions_handler: Option<Observer<Vec<u16>>>,
+    extensions_to_send: Option<Receiver<RawFrame>>,
+    requests: Option<(Sender<Request>, Receiver<Request>)>,
+    shutdown: Option<ShutdownHandle>,
+    origins: Origins,
 }
 
 #[derive(Debug)]
@@ -144,21 +755,39 @@ pub enum ClientBuilderError {
     TryToAddProtocolAfterAddingSetupConnection,
     TryToAddSetupConnectionAfterAddingProtocol,
     CanNotHaveMoreThan1Server,
+    CanNotHaveAServerAndUpstreams,
+    InvalidBackoffRange,
+    /// A handler with a reply is already registered for a message of this key
+    OwnerAlreadyRegistered(HandlerKey),
+    MessageHandlerAlreadyRegistered,
+    /// The server did not complete the Noise handshake in time
+    HandshakeTimeout,
 }
 
-// TODO a way for the caller to add a channel where it can receive the SetupConnection.Success or
-// Error message.
-// Something like with_custom_setup_connection_handler() ?
 impl ClientBuilder {
     pub fn new() -> Self {
         Self {
             from_server: None,
             to_server: None,
             server_auth_key: None,
+            upstreams: vec![],
+            backoff: Backoff::default(),
+            timeouts: Timeouts::default(),
             handlers: vec![],
             messages_to_send: None,
+            interceptors: vec![],
             setup_connection_message: None,
             protocol: None,
+            invalid_frame_policy: InvalidFramePolicy::default(),
+            invalid_frame_handler: None,
+            setup_connection_handler: None,
+            connection_state_handler: None,
+            extensions: ExtensionRegistry::default(),
+            extensions_handler: None,
+            extensions_to_send: None,
+            requests: None,
+            shutdown: None,
+            origins: Origins::default(),
         }
     }
     pub fn try_with_server(
@@ -166,6 +795,9 @@ impl ClientBuilder {
         from_server: Receiver<Frame_>,
         to_server: Sender<Frame_>,
     ) -> Result<&mut Self, ClientBuilderError> {
+        if !self.upstreams.is_empty() {
+            return Err(ClientBuilderError::CanNotHaveAServerAndUpstreams);
+        }
         if self.from_server.is_none() && self.to_server.is_none() {
             self.from_server = Some(from_server);
             self.to_server = Some(to_server);
@@ -178,22 +810,83 @@ impl ClientBuilder {
         &mut self,
         stream: TcpStream,
     ) -> Result<&mut Self, ClientBuilderError> {
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_server_stream(stream, &peer).await?;
+        self.origins.set_address(0, address);
+        Ok(self)
+    }
+    /// Like `try_add_server` but the frames are exchanged without the Noise handshake and
+    /// without encryption. Only meant to talk with a local server (a Template Provider, a
+    /// sidecar) or to debug with a packet capture.
+    pub fn try_add_server_unencrypted(
+        &mut self,
+        stream: TcpStream,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_server_stream_unencrypted(stream, &peer)?;
+        self.origins.set_address(0, address);
+        Ok(self)
+    }
+    /// Like `try_add_server` but over a unix domain socket.
+    pub async fn try_add_server_unix(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        self.add_server_stream(stream, "unix socket").await
+    }
+    /// Like `try_add_server_unencrypted` but over a unix domain socket.
+    pub fn try_add_server_unix_unencrypted(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        self.add_server_stream_unencrypted(stream, "unix socket")
+    }
+    /// Like `try_add_server` but over any stream, for example a socket that goes through a
+    /// SOCKS proxy or a custom tunnel. The Noise handshake and the SV2 framing are done on top
+    /// of the stream.
+    pub async fn try_add_server_stream<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ClientBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_server_stream(stream, "stream").await
+    }
+    /// Like `try_add_server_stream` but without the Noise handshake and without encryption, see
+    /// `try_add_server_unencrypted`.
+    pub fn try_add_server_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ClientBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_server_stream_unencrypted(stream, "stream")
+    }
+    async fn add_server_stream<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ClientBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if !self.upstreams.is_empty() {
+            return Err(ClientBuilderError::CanNotHaveAServerAndUpstreams);
+        }
         if self.from_server.is_none() && self.to_server.is_none() {
-            let initiator = match self.server_auth_key {
-                Some(key) => Initiator::from_raw_k(key.into_bytes())
-                    .expect("Pub key is already checked for validity"),
-                None => Initiator::without_pk().expect("This fn call can not fail"),
-            };
-
-            if let Ok((receiver_from_client, send_to_client, _, _)) =
-                Connection::new::<'static, PoolMessages<'static>>(
-                    stream,
-                    HandshakeRole::Initiator(initiator),
-                )
-                .await
-            {
-                self.from_server = Some(receiver_from_client);
-                self.to_server = Some(send_to_client);
+            let connection = timeout(
+                self.timeouts.handshake,
+                noise_connection(stream, peer, initiator(self.server_auth_key)),
+            )
+            .await
+            .map_err(|_| ClientBuilderError::HandshakeTimeout)?;
+            if let Some((receiver_from_server, send_to_server, _)) = connection {
+                self.from_server = Some(receiver_from_server);
+                self.to_server = Some(send_to_server);
                 Ok(self)
             } else {
                 Err(ClientBuilderError::ImpossibleToCompleteHandShakeWithUpstream)
@@ -202,6 +895,26 @@ impl ClientBuilder {
             Err(ClientBuilderError::CanNotHaveMoreThan1Server)
         }
     }
+    fn add_server_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ClientBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if !self.upstreams.is_empty() {
+            return Err(ClientBuilderError::CanNotHaveAServerAndUpstreams);
+        }
+        if self.from_server.is_none() && self.to_server.is_none() {
+            let (receiver_from_server, send_to_server, _) = plain_connection(stream, peer);
+            self.from_server = Some(receiver_from_server);
+            self.to_server = Some(send_to_server);
+            Ok(self)
+        } else {
+            Err(ClientBuilderError::CanNotHaveMoreThan1Server)
+        }
+    }
     pub fn with_protocol(&mut self, protocol: Protocol) -> Result<&mut Self, ClientBuilderError> {
         if self.setup_connection_message.is_some() {
             eprintln!("You can select a protocol or add a setup connection message not both");
@@ -219,6 +932,93 @@ impl ClientBuilder {
         Ok(self)
     }
 
+    /// Add an upstream that the client connects to, the client connects to the first
+    /// available upstream in the order they have been added. If the connection is lost the
+    /// client connects again, re-running the handshake and the SetupConnection, and keeps using
+    /// the same handlers. `auth_key` is the upstream authority public key, if `None` the
+    /// upstream is not authenticated.
+    pub fn add_upstream(
+        &mut self,
+        address: impl Into<String>,
+        auth_key: Option<String>,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        if self.from_server.is_some() || self.to_server.is_some() {
+            return Err(ClientBuilderError::CanNotHaveAServerAndUpstreams);
+        }
+        let auth_key = match auth_key {
+            Some(auth_key) => Some(auth_key.parse()?),
+            None => None,
+        };
+        self.upstreams.push(Upstream {
+            address: address.into(),
+            transport: Transport::Noise(auth_key),
+        });
+        Ok(self)
+    }
+    /// Like `add_upstream` but the frames are exchanged without the Noise handshake and without
+    /// encryption. Only meant to talk with a local upstream (a Template Provider, a sidecar) or
+    /// to debug with a packet capture.
+    pub fn add_upstream_unencrypted(
+        &mut self,
+        address: impl Into<String>,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        if self.from_server.is_some() || self.to_server.is_some() {
+            return Err(ClientBuilderError::CanNotHaveAServerAndUpstreams);
+        }
+        self.upstreams.push(Upstream {
+            address: address.into(),
+            transport: Transport::Unencrypted,
+        });
+        Ok(self)
+    }
+    /// Delay before connecting again when no upstream is available, the delay starts at
+    /// `initial` and doubles after each failed attempt up to `max`. Default is 1s to 60s.
+    pub fn with_reconnect_backoff(
+        &mut self,
+        initial: Duration,
+        max: Duration,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        if initial > max {
+            return Err(ClientBuilderError::InvalidBackoffRange);
+        }
+        self.backoff = Backoff { initial, max };
+        Ok(self)
+    }
+    /// Maximum time to complete the Noise handshake with the server, for upstreams added with
+    /// `add_upstream` it includes the TCP connection. Default is 10 seconds.
+    pub fn with_handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+    /// Maximum time for the server to answer the SetupConnection, and the extensions request when
+    /// some extensions are required, the client fails with `SetupTimeout` after it. Default is 10 seconds.
+    pub fn with_setup_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.setup = timeout;
+        self
+    }
+    /// The client fails with `IdleTimeout` when nothing is received from the server for longer
+    /// than `timeout`, with upstreams it connects again. By default there is no idle timeout.
+    pub fn with_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+    /// Receive a `ConnectionState` every time the client connects to, or is disconnected from,
+    /// one of the upstreams added with `add_upstream`. The client does not wait for the
+    /// receiver, the states that do not fit in the channel are dropped.
+    pub fn add_connection_state_handler(&mut self) -> Receiver<ConnectionState> {
+        self.add_connection_state_handler_with_config(NEVER_WAIT).0
+    }
+    /// Like `add_connection_state_handler` but with the capacity of the channel and what to do
+    /// when it is full. With `OverflowPolicy::Block` a slow receiver stalls the reconnections.
+    pub fn add_connection_state_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<ConnectionState>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.connection_state_handler = Some(observer);
+        (r, dropped)
+    }
+
     pub fn with_custom_setup_connection(
         &mut self,
         setup_connection: SetupConnection,
@@ -233,40 +1033,306 @@ impl ClientBuilder {
         Ok(self)
     }
 
-    pub fn add_handler(&mut self, message_type: u8) -> Receiver<PoolMessages<'static>> {
-        let (s, r) = channel(3);
+    /// Observe the messages of type `message_type` sent by the upstream, every handler registered
+    /// for a message type receives its messages.
+    pub fn add_handler(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<PoolMessages<'static>> {
+        self.add_handler_with_config(message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_handler` but with the capacity of the channel and what to do when it is full,
+    /// the returned counter tells how many messages have been dropped.
+    pub fn add_handler_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<PoolMessages<'static>>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
         let channel = MessageChannel {
-            message_type,
+            key: message_type.into(),
             expect_from: Remote::Server,
             receiver: None,
-            sender: s,
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
         };
-        self.handlers.push(channel);
-        r
+        self.handlers.push(channel.into());
+        (r, dropped)
     }
+    /// Receive the messages of type `message_type` sent by the upstream, for each of them a reply
+    /// must be sent back with the returned sender. A message type has at most one handler with a
+    /// reply, it receives the messages after the handlers without reply.
     pub fn add_handler_with_sender(
         &mut self,
-        message_type: u8,
-    ) -> (
-        Receiver<PoolMessages<'static>>,
-        Sender<PoolMessages<'static>>,
-    ) {
-        let (s, r) = channel(3);
-        let (s1, r1) = channel(3);
+        message_type: impl Into<HandlerKey>,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+        ),
+        ClientBuilderError,
+    > {
+        let (r, s, _) =
+            self.add_handler_with_sender_with_config(message_type, HandlerConfig::default())?;
+        Ok((r, s))
+    }
+    /// Like `add_handler_with_sender` but with the capacity of the channels and what to do when
+    /// they are full, like `add_handler_with_config`.
+    pub fn add_handler_with_sender_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+            DropCounter,
+        ),
+        ClientBuilderError,
+    > {
+        let (s, r) = channel(config.channel_capacity());
+        let (s1, r1) = channel(config.capacity.max(1));
+        let dropped = DropCounter::default();
         let channel = MessageChannel {
-            message_type,
+            key: message_type.into(),
             expect_from: Remote::Server,
-            receiver: Some(r1),
-            sender: s,
+            receiver: Some(HandlerReceiver::Plain(r1)),
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
         };
-        self.handlers.push(channel);
-        (r, s1)
+        push_handler(&mut self.handlers, channel)?;
+        Ok((r, s1, dropped))
+    }
+    /// Like `add_handler` but the message type comes from `T` and the messages are received as
+    /// `T`, eg `add_typed_handler::<NewExtendedMiningJob<'static>>()`.
+    pub fn add_typed_handler<T: TypedMessage>(&mut self) -> Receiver<T> {
+        self.add_typed_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_typed_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_typed_handler_with_config<T: TypedMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: T::MESSAGE_TYPE.into(),
+            expect_from: Remote::Server,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(Typed(s))),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Like `add_handler` but every message comes in an `Inbound`, with the address of the
+    /// upstream and the SetupConnection sent to it.
+    pub fn add_inbound_handler(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<Inbound> {
+        self.add_inbound_handler_with_config(message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_inbound_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_inbound_handler_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<Inbound>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Server,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(InboundSender {
+                sender: s,
+                origins: self.origins.clone(),
+            })),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Handle the messages sent by the upstream with `handler`, a `Reply::Send` is sent back to
+    /// the upstream. Only one `Handler` can be registered, it does not receive the message types
+    /// that have a handler with a reply.
+    pub fn add_message_handler(
+        &mut self,
+        handler: impl Handler,
+    ) -> Result<&mut Self, ClientBuilderError> {
+        push_handler(
+            &mut self.handlers,
+            MessageHandler::new(Remote::Server, handler),
+        )?;
+        Ok(self)
+    }
+    /// Request the extension `extension_type` to the upstream after the `SetupConnection`. When at
+    /// least one extension is registered the client sends a `RequestExtensions`, it only waits
+    /// for the answer of the upstream before starting if one of them is required.
+    pub fn add_extension(&mut self, extension_type: u16) -> &mut Self {
+        self.extensions.add(extension_type, false);
+        self
+    }
+    /// Like `add_extension` but `start` fails with `ClientError::ExtensionsRejected` if the
+    /// upstream does not support the extension.
+    pub fn add_required_extension(&mut self, extension_type: u16) -> &mut Self {
+        self.extensions.add(extension_type, true);
+        self
+    }
+    /// Receive the messages of type `T` of an extension sent by the upstream, the extension of `T`
+    /// is requested like with `add_extension`.
+    pub fn add_extension_handler<T: ExtensionMessage>(&mut self) -> Receiver<T> {
+        self.add_extension_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_extension_handler` but with the capacity of the channel and what to do when it
+    /// is full, like `add_handler_with_config`.
+    pub fn add_extension_handler_with_config<T: ExtensionMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        self.extensions.add(T::EXTENSION_TYPE, false);
+        let sender = RawChannel::typed(s, Overflow::new(config, dropped.clone()));
+        self.handlers
+            .push(RawHandler::typed::<T>(Remote::Server, sender).into());
+        (r, dropped)
+    }
+    /// Frames sent here go to the upstream, eg `RawFrame::from_message(&message)` for an extension
+    /// message. They do not go through the outbound interceptors. A payload of 2^24 bytes or more
+    /// makes `start` fail with `ClientError::PayloadTooLong`.
+    pub fn add_extension_sender(&mut self) -> Sender<RawFrame> {
+        self.add_extension_sender_with_capacity(3)
+    }
+    /// Like `add_extension_sender` but with a channel that holds `capacity` frames.
+    pub fn add_extension_sender_with_capacity(&mut self, capacity: usize) -> Sender<RawFrame> {
+        let (s, r) = channel(capacity.max(1));
+        self.extensions_to_send = Some(r);
+        s
+    }
+    /// Receive the extensions negotiated with the upstream, every time the client connects. When
+    /// no extension is required the client does not wait for the answer of the upstream, they
+    /// are sent here once it arrives and an upstream that never answers negotiates none.
+    pub fn add_negotiated_extensions_handler(&mut self) -> Receiver<Vec<u16>> {
+        self.add_negotiated_extensions_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_negotiated_extensions_handler` but with the capacity of the channel and what to
+    /// do when it is full, like `add_handler_with_config`.
+    pub fn add_negotiated_extensions_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<Vec<u16>>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.extensions_handler = Some(observer);
+        (r, dropped)
     }
     pub fn add_message_sender(&mut self) -> Sender<PoolMessages<'static>> {
-        let (s, r) = channel(3);
+        self.add_message_sender_with_capacity(3)
+    }
+    /// Like `add_message_sender` but with a channel that holds `capacity` messages.
+    pub fn add_message_sender_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Sender<PoolMessages<'static>> {
+        let (s, r) = channel(capacity.max(1));
         self.messages_to_send = Some(r);
         s
     }
+    /// Every message sent with the message sender goes through the interceptor before reaching
+    /// the upstream: the interceptor receives it and must send back the message to send in its
+    /// place. Interceptors are applied in the order they are added.
+    pub fn add_outbound_interceptor(
+        &mut self,
+    ) -> (
+        Receiver<PoolMessages<'static>>,
+        Sender<PoolMessages<'static>>,
+    ) {
+        self.add_outbound_interceptor_with_capacity(3)
+    }
+    /// Like `add_outbound_interceptor` but with channels that hold `capacity` messages.
+    pub fn add_outbound_interceptor_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> (
+        Receiver<PoolMessages<'static>>,
+        Sender<PoolMessages<'static>>,
+    ) {
+        let (s, r) = channel(capacity.max(1));
+        let (s1, r1) = channel(capacity.max(1));
+        self.interceptors.push(Interceptor {
+            sender: s,
+            receiver: r1,
+        });
+        (r, s1)
+    }
+    /// A `Requester` to send requests to the upstream and wait for their response, every requester
+    /// uses the same `request_id` sequence. Responses are not sent to the handlers.
+    pub fn add_requester(&mut self, timeout: Duration) -> Requester {
+        self.add_requester_with_capacity(timeout, 3)
+    }
+    /// Like `add_requester` but the channel of the requests holds `capacity` of them. The
+    /// requesters share that channel, it is created by the first one added.
+    pub fn add_requester_with_capacity(&mut self, timeout: Duration, capacity: usize) -> Requester {
+        let (sender, _) = self
+            .requests
+            .get_or_insert_with(|| channel(capacity.max(1)));
+        Requester::new(0, sender.clone(), timeout)
+    }
+    /// A `ShutdownHandle` to stop the client, the goodbye messages for `Remote::Server` are sent
+    /// to the upstream.
+    pub fn add_shutdown_handle(&mut self) -> ShutdownHandle {
+        self.shutdown
+            .get_or_insert_with(ShutdownHandle::new)
+            .clone()
+    }
+    /// Receive the `SetupConnection.Success` sent by the upstream, with the version and the flags
+    /// used for the connection. A `SetupConnection.Error` makes `start` fail with
+    /// `ClientError::SetupConnectionRejected`. The client does not wait for the receiver, the
+    /// messages that do not fit in the channel are dropped.
+    pub fn add_setup_connection_handler(&mut self) -> Receiver<SetupConnectionSuccess> {
+        self.add_setup_connection_handler_with_config(NEVER_WAIT).0
+    }
+    /// Like `add_setup_connection_handler` but with the capacity of the channel and what to do
+    /// when it is full. With `OverflowPolicy::Block` the time spent waiting for the receiver
+    /// counts in the setup timeout.
+    pub fn add_setup_connection_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<SetupConnectionSuccess>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.setup_connection_handler = Some(observer);
+        (r, dropped)
+    }
+    /// What to do when the upstream sends a frame that can not be decoded, the default is to
+    /// close the connection.
+    pub fn with_invalid_frame_policy(&mut self, policy: InvalidFramePolicy) -> &mut Self {
+        self.invalid_frame_policy = policy;
+        self
+    }
+    /// Receive an `InvalidFrame` every time the upstream sends a frame that can not be decoded.
+    pub fn add_invalid_frame_handler(&mut self) -> Receiver<InvalidFrame> {
+        self.add_invalid_frame_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_invalid_frame_handler` but with the capacity of the channel and what to do when
+    /// it is full, like `add_handler_with_config`.
+    pub fn add_invalid_frame_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<InvalidFrame>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.invalid_frame_handler = Some(observer);
+        (r, dropped)
+    }
     fn get_protocol(&self) -> Result<Protocol, ClientBuilderError> {
         match (self.protocol, &self.setup_connection_message) {
             (Some(protocol), None) => Ok(protocol),
@@ -280,14 +1346,37 @@ impl ClientBuilder {
     }
     pub fn try_build(self) -> Result<Client, ClientBuilderError> {
         let protocol = self.get_protocol()?;
-        if let (Some(from_server), Some(to_server)) = (self.from_server, self.to_server) {
+        let connection = match (self.from_server, self.to_server) {
+            (Some(from_server), Some(to_server)) => Some((from_server, to_server)),
+            _ => None,
+        };
+        if connection.is_some() || !self.upstreams.is_empty() {
             Ok(Client {
-                from_server,
-                to_server,
-                handlers: self.handlers,
+                connection,
+                upstreams: self.upstreams,
+                backoff: self.backoff,
+                timeouts: self.timeouts,
+                handlers: Dispatcher::new(Remote::Server, self.handlers),
                 messages_to_send: self.messages_to_send,
+                interceptors: self.interceptors,
                 setup_connection_message: self.setup_connection_message,
                 protocol,
+                invalid_frames: InvalidFrames {
+                    policy: self.invalid_frame_policy,
+                    observer: self.invalid_frame_handler,
+                },
+                setup_connection_handler: self.setup_connection_handler,
+                connection_state_handler: self.connection_state_handler,
+                extensions: Extensions {
+                    registry: self.extensions,
+                    handler: self.extensions_handler,
+                    awaiting_answer: false,
+                },
+                extensions_to_send: self.extensions_to_send,
+                requests: self.requests.map(|(_, receiver)| receiver),
+                pending_requests: PendingRequests::default(),
+                shutdown: ShutdownSignal::from_handle(self.shutdown),
+                origins: self.origins,
             })
         } else {
             Err(ClientBuilderError::IncompleteBuilder)
@@ -295,8 +1384,39 @@ impl ClientBuilder {
     }
 }
 
+impl From<FrameError> for ClientError {
+    fn from(value: FrameError) -> Self {
+        Self::InvalidFrame(value)
+    }
+}
+
+impl From<MessageChannelError> for ClientError {
+    fn from(value: MessageChannelError) -> Self {
+        match value {
+            MessageChannelError::InvalidFrame(e) => Self::InvalidFrame(e),
+            MessageChannelError::HandlerDropped(mt) => Self::HandlerDropped(mt),
+            MessageChannelError::HandlerOverflow(mt) => Self::HandlerOverflow(mt),
+        }
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<KeyUtilsError> for ClientBuilderError {
     fn from(value: KeyUtilsError) -> Self {
         Self::KeyError(value)
     }
 }
+
+impl From<OwnerConflict> for ClientBuilderError {
+    fn from(value: OwnerConflict) -> Self {
+        match value {
+            OwnerConflict::Key(key) => Self::OwnerAlreadyRegistered(key),
+            OwnerConflict::MessageHandler => Self::MessageHandlerAlreadyRegistered,
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use codec_sv2::{
+    framing_sv2::framing::Frame as EitherFrame, Encoder, HandshakeRole, Initiator, NoiseEncoder,
+    StandardNoiseDecoder, State,
+};
+use const_sv2::{
+    INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE, RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
+    SV2_FRAME_HEADER_SIZE,
+};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use roles_logic_sv2::parsers::PoolMessages;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{channel, Receiver, Sender},
+    task::{self, AbortHandle},
+    time::{error::Elapsed, timeout},
+};
+
+use crate::server_helpers::{DEFAULT_PUB_KEY, DEFAULT_SEC_KEY};
+use crate::Frame_;
+use crate::StdFrame;
+
+/// The channels to exchange frames with the remote and the handles of the tasks that read from
+/// and write to the stream.
+pub(crate) type Connection = (Receiver<Frame_>, Sender<Frame_>, [AbortHandle; 2]);
+
+/// Deadlines of a connection, the handshake and the SetupConnection default to 10 seconds and
+/// there is no idle timeout by default.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    /// Maximum time to complete the Noise handshake, including the TCP connection for upstreams
+    pub handshake: Duration,
+    /// Maximum time to complete the SetupConnection exchange
+    pub setup: Duration,
+    /// Maximum time without receiving any frame from the remote
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            setup: Duration::from_secs(10),
+            idle: None,
+        }
+    }
+}
+
+/// Receive from `recv`, failing if nothing is received within `idle`.
+pub(crate) async fn recv_within<T>(
+    recv: &mut Receiver<T>,
+    idle: Option<Duration>,
+) -> Result<Option<T>, Elapsed> {
+    match idle {
+        Some(idle) => timeout(idle, recv.recv()).await,
+        None => Ok(recv.recv().await),
+    }
+}
+
+/// Size of the in-memory pipe of `pair` and `noise_pair`.
+const PAIR_BUFFER_SIZE: usize = 64 * 1024;
+
+/// One end of a connection, `receiver` gets the frames sent by the other end and `sender`
+/// sends frames to it. Give it to `try_with_server` or `try_with_client`.
+pub struct Endpoint {
+    pub receiver: Receiver<Frame_>,
+    pub sender: Sender<Frame_>,
+}
+
+impl From<Connection> for Endpoint {
+    fn from((receiver, sender, _): Connection) -> Self {
+        Self { receiver, sender }
+    }
+}
+
+/// Two connected in-memory endpoints, the first for the client and the second for the server.
+/// The frames are serialized and parsed as on a real connection but without encryption, useful
+/// to test without binding ports.
+pub fn pair() -> (Endpoint, Endpoint) {
+    let (client, server) = duplex(PAIR_BUFFER_SIZE);
+    (
+        plain_connection(client, "in-memory server").into(),
+        plain_connection(server, "in-memory client").into(),
+    )
+}
+
+/// Like `pair` but the endpoints complete a Noise handshake and encrypt the frames. The server
+/// end uses the authority keys of the stratum examples.
+pub async fn noise_pair() -> (Endpoint, Endpoint) {
+    let (client, server) = duplex(PAIR_BUFFER_SIZE);
+    let pub_key: Secp256k1PublicKey = DEFAULT_PUB_KEY.parse().expect("Invalid default pub key");
+    let sec_key: Secp256k1SecretKey = DEFAULT_SEC_KEY.parse().expect("Invalid default sec key");
+    let initiator = Initiator::from_raw_k(pub_key.into_bytes()).expect("Invalid default pub key");
+    let responder = codec_sv2::Responder::from_authority_kp(
+        &pub_key.into_bytes(),
+        &sec_key.into_bytes(),
+        Duration::from_secs(10000),
+    )
+    .expect("invalid key pair");
+    let (client, server) = tokio::join!(
+        noise_connection(
+            client,
+            "in-memory server",
+            HandshakeRole::Initiator(initiator)
+        ),
+        noise_connection(
+            server,
+            "in-memory client",
+            HandshakeRole::Responder(responder)
+        ),
+    );
+    (
+        client.expect("In-memory handshake can not fail").into(),
+        server.expect("In-memory handshake can not fail").into(),
+    )
+}
+
+/// Exchange SV2 frames over `stream` without the Noise handshake and without encryption, the
+/// frames are sent as they are serialized. Only meant for local deployments and debugging.
+pub(crate) fn plain_connection<S>(stream: S, peer: impl Into<String>) -> Connection
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let address = peer.into();
+    let (mut reader, mut writer) = split(stream);
+    let (sender_incoming, receiver_incoming) = channel(10);
+    let (sender_outgoing, mut receiver_outgoing) = channel::<Frame_>(10);
+
+    let recv_address = address.clone();
+    let recv_task = task::spawn(async move {
+        loop {
+            let mut header = [0; SV2_FRAME_HEADER_SIZE];
+            if let Err(e) = reader.read_exact(&mut header).await {
+                eprintln!("Disconnected from {recv_address} while reading: {e}");
+                break;
+            }
+            // The payload length is the last 3 bytes of the header
+            let length = u32::from_le_bytes([header[3], header[4], header[5], 0]) as usize;
+            let mut bytes = vec![0; SV2_FRAME_HEADER_SIZE + length];
+            bytes[..SV2_FRAME_HEADER_SIZE].copy_from_slice(&header);
+            if let Err(e) = reader.read_exact(&mut bytes[SV2_FRAME_HEADER_SIZE..]).await {
+                eprintln!("Disconnected from {recv_address} while reading: {e}");
+                break;
+            }
+            let Ok(frame) = StdFrame::from_bytes(bytes.into()) else {
+                eprintln!("Received invalid frame from {recv_address}");
+                break;
+            };
+            if sender_incoming.send(frame.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let send_task = task::spawn(async move {
+        let mut encoder = Encoder::<PoolMessages<'static>>::new();
+        while let Some(frame) = receiver_outgoing.recv().await {
+            let EitherFrame::Sv2(frame) = frame else {
+                eprintln!("Handshake frames can not be sent without Noise, to {address}");
+                continue;
+            };
+            let bytes = match encoder.encode(frame) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("Impossible to encode frame for {address}: {e:?}");
+                    break;
+                }
+            };
+            if let Err(e) = writer.write_all(bytes).await {
+                eprintln!("Disconnected from {address} while writing: {e}");
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    (
+        receiver_incoming,
+        sender_outgoing,
+        [recv_task.abort_handle(), send_task.abort_handle()],
+    )
+}
+
+/// Complete the Noise handshake over `stream` and then exchange encrypted SV2 frames. The
+/// handshake state belongs to the connection, so any number of handshakes can run at the same
+/// time.
+pub(crate) async fn noise_connection<S>(
+    stream: S,
+    peer: impl Into<String>,
+    role: HandshakeRole,
+) -> Option<Connection>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let address = peer.into();
+    let (mut reader, mut writer) = split(stream);
+    let state = match handshake(&mut reader, &mut writer, role).await {
+        Ok(state) => Arc::new(Mutex::new(state)),
+        Err(e) => {
+            eprintln!("Impossible to complete handshake with {address}: {e}");
+            return None;
+        }
+    };
+    let (sender_incoming, receiver_incoming) = channel(10);
+    let (sender_outgoing, mut receiver_outgoing) = channel::<Frame_>(10);
+
+    let recv_address = address.clone();
+    let recv_state = state.clone();
+    let recv_task = task::spawn(async move {
+        let mut decoder = StandardNoiseDecoder::<PoolMessages<'static>>::new();
+        loop {
+            if let Err(e) = reader.read_exact(decoder.writable()).await {
+                eprintln!("Disconnected from {recv_address} while reading: {e}");
+                break;
+            }
+            let decoded = decoder.next_frame(&mut recv_state.lock().expect("poisoned lock"));
+            match decoded {
+                Ok(frame) => {
+                    if sender_incoming.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                Err(codec_sv2::Error::MissingBytes(_)) => (),
+                Err(e) => {
+                    eprintln!("Received invalid frame from {recv_address}: {e:?}");
+                    break;
+                }
+            }
+        }
+        drop(sender_incoming);
+        // The frames borrow the memory of the decoder, it can only be dropped once they
+        // are dropped
+        wait_droppable(|| decoder.droppable()).await;
+    });
+
+    let send_task = task::spawn(async move {
+        let mut encoder = NoiseEncoder::<PoolMessages<'static>>::new();
+        while let Some(frame) = receiver_outgoing.recv().await {
+            let encoded = encoder.encode(frame, &mut state.lock().expect("poisoned lock"));
+            let bytes = match encoded {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("Impossible to encode frame for {address}: {e:?}");
+                    break;
+                }
+            };
+            if let Err(e) = writer.write_all(bytes.as_ref()).await {
+                eprintln!("Disconnected from {address} while writing: {e}");
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+        wait_droppable(|| encoder.droppable()).await;
+    });
+
+    Some((
+        receiver_incoming,
+        sender_outgoing,
+        [recv_task.abort_handle(), send_task.abort_handle()],
+    ))
+}
+
+/// Exchange the handshake messages, they are sent as they are without SV2 framing, and return
+/// the state in transport mode.
+async fn handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    role: HandshakeRole,
+) -> Result<State, String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let initiator = matches!(role, HandshakeRole::Initiator(_));
+    let mut state = State::initialized(role);
+    if initiator {
+        let first_message = state.step_0().map_err(|e| format!("{e:?}"))?;
+        writer
+            .write_all(&first_message.get_payload_when_handshaking())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut second_message = [0; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+        reader
+            .read_exact(&mut second_message)
+            .await
+            .map_err(|e| e.to_string())?;
+        state.step_2(second_message).map_err(|e| format!("{e:?}"))
+    } else {
+        let mut first_message = [0; RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+        reader
+            .read_exact(&mut first_message)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (second_message, transport) =
+            state.step_1(first_message).map_err(|e| format!("{e:?}"))?;
+        writer
+            .write_all(&second_message.get_payload_when_handshaking())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(transport)
+    }
+}
+
+async fn wait_droppable(droppable: impl Fn() -> bool) {
+    while !droppable() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Address of the remote end of a `TcpStream`, used in the logs.
+pub(crate) fn peer_name(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default()
+}
+
+/// Whether the local end of a connection is on the loopback interface, unencrypted connections
+/// are only accepted from local peers.
+pub(crate) fn is_loopback(local_addr: std::io::Result<SocketAddr>) -> bool {
+    local_addr.is_ok_and(|addr| addr.ip().is_loopback())
+}
@@ -0,0 +1,593 @@
+use codec_sv2::framing_sv2::framing::Frame as EitherFrame;
+use std::{future::Future, pin::Pin, sync::Arc};
+use tokio::sync::mpsc::Sender;
+
+use crate::message_channel::{
+    ConnectionId, FrameError, MessageChannelError, MessageType, Observer, Outcome, Overflow,
+    OverflowError, Remote, EXTENSION_TYPE_MASK,
+};
+use crate::Frame_;
+use crate::StdFrame;
+
+/// The extension used to negotiate the other extensions after the `SetupConnection`.
+pub const EXTENSION_TYPE_EXTENSIONS_NEGOTIATION: u16 = 0x0001;
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS: MessageType = 0x00;
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS: MessageType = 0x01;
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR: MessageType = 0x02;
+
+/// A message of a protocol extension, or a custom message, sent in frames with a non zero
+/// `extension_type`. For a struct that derives `binary_sv2::Serialize` and
+/// `binary_sv2::Deserialize`, `to_payload` and `from_payload` can use `binary_sv2::to_bytes` and
+/// `binary_sv2::from_bytes`.
+pub trait ExtensionMessage: Sized + Send + 'static {
+    /// The extension id, without the `channel_msg` bit
+    const EXTENSION_TYPE: u16;
+    const MESSAGE_TYPE: MessageType;
+    const CHANNEL_MSG: bool = false;
+
+    fn to_payload(&self) -> Vec<u8>;
+
+    /// `None` if the payload is not a valid message
+    fn from_payload(payload: &[u8]) -> Option<Self>;
+}
+
+/// The length of a payload is encoded on 3 bytes in the header of its frame.
+const MAX_PAYLOAD_LENGTH: usize = (1 << 24) - 1;
+
+/// A frame of an extension, received by a raw handler.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawFrame {
+    /// The extension id, without the `channel_msg` bit
+    pub extension_type: u16,
+    pub channel_msg: bool,
+    pub message_type: MessageType,
+    pub payload: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn from_message<T: ExtensionMessage>(message: &T) -> Self {
+        Self {
+            extension_type: T::EXTENSION_TYPE,
+            channel_msg: T::CHANNEL_MSG,
+            message_type: T::MESSAGE_TYPE,
+            payload: message.to_payload(),
+        }
+    }
+
+    /// Decode the frame as a `T`, `None` if it is the frame of another message or if the payload
+    /// is not valid.
+    pub fn decode<T: ExtensionMessage>(&self) -> Option<T> {
+        if self.extension_type != T::EXTENSION_TYPE || self.message_type != T::MESSAGE_TYPE {
+            return None;
+        }
+        T::from_payload(&self.payload)
+    }
+
+    fn from_frame(frame: &mut Frame_) -> Option<Self> {
+        let EitherFrame::Sv2(frame) = frame else {
+            return None;
+        };
+        let header = frame.get_header()?;
+        Some(Self {
+            extension_type: header.ext_type() & EXTENSION_TYPE_MASK,
+            channel_msg: header.ext_type() & !EXTENSION_TYPE_MASK != 0,
+            message_type: header.msg_type(),
+            payload: frame.payload().to_vec(),
+        })
+    }
+
+    /// Whether the payload is too long for a frame, its length must be below 2^24 bytes.
+    pub(crate) fn is_too_long(&self) -> bool {
+        self.payload.len() > MAX_PAYLOAD_LENGTH
+    }
+
+    /// `None` when the payload is too long for a frame.
+    pub(crate) fn into_frame(self) -> Option<StdFrame> {
+        if self.is_too_long() {
+            return None;
+        }
+        let mut extension_type = self.extension_type & EXTENSION_TYPE_MASK;
+        if self.channel_msg {
+            extension_type |= !EXTENSION_TYPE_MASK;
+        }
+        let length = (self.payload.len() as u32).to_le_bytes();
+        let mut bytes = Vec::with_capacity(6 + self.payload.len());
+        bytes.extend_from_slice(&extension_type.to_le_bytes());
+        bytes.push(self.message_type);
+        bytes.extend_from_slice(&length[..3]);
+        bytes.extend_from_slice(&self.payload);
+        let frame = StdFrame::from_bytes(bytes.into())
+            .expect("The header of a raw frame matches its payload");
+        Some(frame)
+    }
+}
+
+/// Decode `frame` as a `T`, `None` if it is the frame of another message.
+pub(crate) fn decode_frame<T: ExtensionMessage>(
+    frame: &mut Frame_,
+) -> Result<Option<T>, FrameError> {
+    let EitherFrame::Sv2(sv2_frame) = frame else {
+        return Ok(None);
+    };
+    match sv2_frame.get_header() {
+        // Check the header first to not copy the payload of every frame
+        Some(header)
+            if header.ext_type() & EXTENSION_TYPE_MASK == T::EXTENSION_TYPE
+                && header.msg_type() == T::MESSAGE_TYPE =>
+        {
+            T::from_payload(sv2_frame.payload())
+                .map(Some)
+                .ok_or(FrameError::InvalidPayload(T::MESSAGE_TYPE))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Sender of a raw handler, sends the frames as they are or decoded as an extension message.
+pub(crate) trait RawSender: Send + Sync {
+    fn send(
+        &self,
+        id: ConnectionId,
+        frame: RawFrame,
+    ) -> Pin<Box<dyn Future<Output = Result<(), MessageChannelError>> + Send + '_>>;
+}
+
+fn decode<T: ExtensionMessage>(frame: &RawFrame) -> Result<T, MessageChannelError> {
+    frame
+        .decode()
+        .ok_or(FrameError::InvalidPayload(frame.message_type).into())
+}
+
+/// The channel of a raw handler, `item` builds what the handler receives from a frame. An
+/// extension message is decoded before it is queued, so an invalid frame is reported when it is
+/// dispatched whatever the overflow policy.
+pub(crate) struct RawChannel<T> {
+    sender: Sender<T>,
+    overflow: Overflow<T>,
+    item: fn(ConnectionId, RawFrame) -> Result<T, MessageChannelError>,
+}
+
+impl RawChannel<RawFrame> {
+    /// The frames are sent as they are.
+    pub(crate) fn frames(sender: Sender<RawFrame>, overflow: Overflow<RawFrame>) -> Self {
+        Self {
+            sender,
+            overflow,
+            item: |_, frame| Ok(frame),
+        }
+    }
+}
+
+impl<T: ExtensionMessage> RawChannel<T> {
+    /// The frames are sent decoded as `T`.
+    pub(crate) fn typed(sender: Sender<T>, overflow: Overflow<T>) -> Self {
+        Self {
+            sender,
+            overflow,
+            item: |_, frame| decode(&frame),
+        }
+    }
+}
+
+impl<T: ExtensionMessage> RawChannel<(ConnectionId, T)> {
+    /// The frames are sent decoded as `T`, with the connection that sent them.
+    pub(crate) fn routed(
+        sender: Sender<(ConnectionId, T)>,
+        overflow: Overflow<(ConnectionId, T)>,
+    ) -> Self {
+        Self {
+            sender,
+            overflow,
+            item: |id, frame| decode(&frame).map(|message| (id, message)),
+        }
+    }
+}
+
+impl<T: Send + 'static> RawSender for RawChannel<T> {
+    fn send(
+        &self,
+        id: ConnectionId,
+        frame: RawFrame,
+    ) -> Pin<Box<dyn Future<Output = Result<(), MessageChannelError>> + Send + '_>> {
+        let mt = frame.message_type;
+        Box::pin(async move {
+            let item = (self.item)(id, frame)?;
+            match self.overflow.send(&self.sender, item).await {
+                Ok(()) => Ok(()),
+                Err(OverflowError::Full) => Err(MessageChannelError::HandlerOverflow(mt)),
+                Err(OverflowError::Closed) => Err(MessageChannelError::HandlerDropped(mt)),
+            }
+        })
+    }
+}
+
+/// Sends a copy of the frames of an extension to a raw handler.
+pub(crate) struct RawHandler {
+    pub expect_from: Remote,
+    pub extension_type: u16,
+    /// Only the frames of this message type, every frame of the extension if `None`
+    pub message_type: Option<MessageType>,
+    pub sender: Arc<dyn RawSender>,
+}
+
+impl RawHandler {
+    /// A raw handler for the messages of type `T`.
+    pub(crate) fn typed<T: ExtensionMessage>(
+        expect_from: Remote,
+        sender: impl RawSender + 'static,
+    ) -> Self {
+        Self {
+            expect_from,
+            extension_type: T::EXTENSION_TYPE,
+            message_type: Some(T::MESSAGE_TYPE),
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub(crate) async fn on_frame(
+        &self,
+        id: ConnectionId,
+        frame: &mut Frame_,
+    ) -> Result<(), MessageChannelError> {
+        let Some(raw) = RawFrame::from_frame(frame) else {
+            return Ok(());
+        };
+        if raw.extension_type != self.extension_type
+            || self.message_type.is_some_and(|mt| mt != raw.message_type)
+        {
+            return Ok(());
+        }
+        let result = self.sender.send(id, raw).await;
+        match result {
+            Err(MessageChannelError::HandlerDropped(_)) => eprintln!(
+                "Impossible to send frame to raw handler, for extension: {}",
+                self.extension_type
+            ),
+            Err(MessageChannelError::HandlerOverflow(_)) => eprintln!(
+                "Raw handler channel full, for extension: {}",
+                self.extension_type
+            ),
+            _ => (),
+        }
+        result
+    }
+}
+
+/// Sent by the client after the `SetupConnection.Success` with the extensions it wants to use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestExtensions {
+    pub request_id: u16,
+    pub requested_extensions: Vec<u16>,
+}
+
+/// The requested extensions that the server supports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestExtensionsSuccess {
+    pub request_id: u16,
+    pub supported_extensions: Vec<u16>,
+}
+
+/// Sent by the server when it does not support some of the requested extensions, or when it
+/// requires extensions that have not been requested.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestExtensionsError {
+    pub request_id: u16,
+    pub unsupported_extensions: Vec<u16>,
+    pub required_extensions: Vec<u16>,
+}
+
+fn put_u16(payload: &mut Vec<u8>, value: u16) {
+    payload.extend_from_slice(&value.to_le_bytes());
+}
+
+/// A `SEQ0_64K[U16]`: the number of elements followed by the elements.
+fn put_u16_seq(payload: &mut Vec<u8>, values: &[u16]) {
+    put_u16(payload, values.len() as u16);
+    for value in values {
+        put_u16(payload, *value);
+    }
+}
+
+fn get_u16(payload: &mut &[u8]) -> Option<u16> {
+    let (value, rest) = payload.split_first_chunk::<2>()?;
+    *payload = rest;
+    Some(u16::from_le_bytes(*value))
+}
+
+fn get_u16_seq(payload: &mut &[u8]) -> Option<Vec<u16>> {
+    let len = get_u16(payload)?;
+    (0..len).map(|_| get_u16(payload)).collect()
+}
+
+impl ExtensionMessage for RequestExtensions {
+    const EXTENSION_TYPE: u16 = EXTENSION_TYPE_EXTENSIONS_NEGOTIATION;
+    const MESSAGE_TYPE: MessageType = MESSAGE_TYPE_REQUEST_EXTENSIONS;
+
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        put_u16(&mut payload, self.request_id);
+        put_u16_seq(&mut payload, &self.requested_extensions);
+        payload
+    }
+
+    fn from_payload(mut payload: &[u8]) -> Option<Self> {
+        let message = Self {
+            request_id: get_u16(&mut payload)?,
+            requested_extensions: get_u16_seq(&mut payload)?,
+        };
+        payload.is_empty().then_some(message)
+    }
+}
+
+impl ExtensionMessage for RequestExtensionsSuccess {
+    const EXTENSION_TYPE: u16 = EXTENSION_TYPE_EXTENSIONS_NEGOTIATION;
+    const MESSAGE_TYPE: MessageType = MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS;
+
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        put_u16(&mut payload, self.request_id);
+        put_u16_seq(&mut payload, &self.supported_extensions);
+        payload
+    }
+
+    fn from_payload(mut payload: &[u8]) -> Option<Self> {
+        let message = Self {
+            request_id: get_u16(&mut payload)?,
+            supported_extensions: get_u16_seq(&mut payload)?,
+        };
+        payload.is_empty().then_some(message)
+    }
+}
+
+impl ExtensionMessage for RequestExtensionsError {
+    const EXTENSION_TYPE: u16 = EXTENSION_TYPE_EXTENSIONS_NEGOTIATION;
+    const MESSAGE_TYPE: MessageType = MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR;
+
+    fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        put_u16(&mut payload, self.request_id);
+        put_u16_seq(&mut payload, &self.unsupported_extensions);
+        put_u16_seq(&mut payload, &self.required_extensions);
+        payload
+    }
+
+    fn from_payload(mut payload: &[u8]) -> Option<Self> {
+        let message = Self {
+            request_id: get_u16(&mut payload)?,
+            unsupported_extensions: get_u16_seq(&mut payload)?,
+            required_extensions: get_u16_seq(&mut payload)?,
+        };
+        payload.is_empty().then_some(message)
+    }
+}
+
+/// The extensions registered on a builder, negotiated with the remote after the
+/// `SetupConnection`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExtensionRegistry {
+    pub supported: Vec<u16>,
+    /// Also in `supported`
+    pub required: Vec<u16>,
+}
+
+impl ExtensionRegistry {
+    pub(crate) fn add(&mut self, extension_type: u16, required: bool) {
+        if !self.supported.contains(&extension_type) {
+            self.supported.push(extension_type);
+        }
+        if required && !self.required.contains(&extension_type) {
+            self.required.push(extension_type);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.supported.is_empty()
+    }
+
+    /// The answer of the server to `request`, with the extensions negotiated with the client.
+    fn answer(&self, request: &RequestExtensions) -> (RawFrame, Vec<u16>) {
+        let (negotiated, unsupported): (Vec<u16>, Vec<u16>) = request
+            .requested_extensions
+            .iter()
+            .partition(|extension| self.supported.contains(extension));
+        let required: Vec<u16> = self
+            .required
+            .iter()
+            .filter(|extension| !request.requested_extensions.contains(extension))
+            .copied()
+            .collect();
+        let reply = if unsupported.is_empty() && required.is_empty() {
+            RawFrame::from_message(&RequestExtensionsSuccess {
+                request_id: request.request_id,
+                supported_extensions: negotiated.clone(),
+            })
+        } else {
+            RawFrame::from_message(&RequestExtensionsError {
+                request_id: request.request_id,
+                unsupported_extensions: unsupported,
+                required_extensions: required,
+            })
+        };
+        (reply, negotiated)
+    }
+}
+
+/// Answers the `RequestExtensions` sent by the downstreams of a `Server`.
+pub(crate) struct ExtensionNegotiator {
+    pub extensions: ExtensionRegistry,
+    pub observer: Option<Observer<(ConnectionId, Vec<u16>)>>,
+}
+
+impl ExtensionNegotiator {
+    pub(crate) async fn on_frame(
+        &self,
+        id: ConnectionId,
+        frame: &mut Frame_,
+    ) -> Result<Outcome, MessageChannelError> {
+        let Some(request) = decode_frame::<RequestExtensions>(frame)? else {
+            return Ok(Outcome::Observed);
+        };
+        let (reply, negotiated) = self.extensions.answer(&request);
+        if let Some(observer) = &self.observer {
+            observer
+                .send((id, negotiated), "negotiated extensions")
+                .await;
+        }
+        let reply = reply
+            .into_frame()
+            .expect("Negotiation messages are shorter than a frame");
+        Ok(Outcome::Frames(vec![(id, reply.into())]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_of<T: ExtensionMessage>(message: &T) -> Frame_ {
+        RawFrame::from_message(message)
+            .into_frame()
+            .expect("Short payload")
+            .into()
+    }
+
+    #[test]
+    fn request_extensions_payload() {
+        let message = RequestExtensions {
+            request_id: 0x0102,
+            requested_extensions: vec![0x0002, 0x0a0b],
+        };
+        let payload = message.to_payload();
+        assert_eq!(payload, [0x02, 0x01, 0x02, 0x00, 0x02, 0x00, 0x0b, 0x0a]);
+        assert_eq!(RequestExtensions::from_payload(&payload), Some(message));
+    }
+
+    #[test]
+    fn request_extensions_success_and_error_round_trip() {
+        let success = RequestExtensionsSuccess {
+            request_id: 7,
+            supported_extensions: vec![],
+        };
+        assert_eq!(
+            RequestExtensionsSuccess::from_payload(&success.to_payload()),
+            Some(success)
+        );
+        let error = RequestExtensionsError {
+            request_id: 7,
+            unsupported_extensions: vec![3, 4],
+            required_extensions: vec![5],
+        };
+        assert_eq!(
+            RequestExtensionsError::from_payload(&error.to_payload()),
+            Some(error)
+        );
+    }
+
+    #[test]
+    fn truncated_or_trailing_payloads_are_invalid() {
+        let payload = RequestExtensions {
+            request_id: 1,
+            requested_extensions: vec![2, 3],
+        }
+        .to_payload();
+        for len in 0..payload.len() {
+            assert_eq!(RequestExtensions::from_payload(&payload[..len]), None);
+        }
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert_eq!(RequestExtensions::from_payload(&trailing), None);
+    }
+
+    #[test]
+    fn raw_frame_round_trip() {
+        let raw = RawFrame {
+            extension_type: 0x0123,
+            channel_msg: true,
+            message_type: 0x42,
+            payload: vec![1, 2, 3],
+        };
+        let mut frame: Frame_ = raw.clone().into_frame().unwrap().into();
+        assert_eq!(RawFrame::from_frame(&mut frame), Some(raw));
+    }
+
+    #[test]
+    fn raw_frame_too_long_for_a_frame() {
+        let mut raw = RawFrame {
+            extension_type: 0x0123,
+            channel_msg: false,
+            message_type: 0x42,
+            payload: vec![0; MAX_PAYLOAD_LENGTH + 1],
+        };
+        assert!(raw.clone().into_frame().is_none());
+        raw.payload.pop();
+        assert!(raw.into_frame().is_some());
+    }
+
+    #[test]
+    fn decode_frame_checks_the_header() {
+        let request = RequestExtensions {
+            request_id: 1,
+            requested_extensions: vec![2],
+        };
+        let mut frame = frame_of(&request);
+        assert_eq!(
+            decode_frame::<RequestExtensionsSuccess>(&mut frame),
+            Ok(None)
+        );
+        assert_eq!(
+            decode_frame::<RequestExtensions>(&mut frame),
+            Ok(Some(request))
+        );
+
+        let mut invalid: Frame_ = RawFrame {
+            extension_type: EXTENSION_TYPE_EXTENSIONS_NEGOTIATION,
+            channel_msg: false,
+            message_type: MESSAGE_TYPE_REQUEST_EXTENSIONS,
+            payload: vec![1],
+        }
+        .into_frame()
+        .unwrap()
+        .into();
+        assert_eq!(
+            decode_frame::<RequestExtensions>(&mut invalid),
+            Err(FrameError::InvalidPayload(MESSAGE_TYPE_REQUEST_EXTENSIONS))
+        );
+    }
+
+    #[test]
+    fn registry_answer() {
+        let mut registry = ExtensionRegistry::default();
+        registry.add(2, false);
+        registry.add(3, true);
+        registry.add(3, false);
+        assert_eq!(registry.supported, [2, 3]);
+        assert_eq!(registry.required, [3]);
+
+        let (reply, negotiated) = registry.answer(&RequestExtensions {
+            request_id: 9,
+            requested_extensions: vec![3, 2],
+        });
+        assert_eq!(negotiated, [3, 2]);
+        assert_eq!(
+            reply.decode(),
+            Some(RequestExtensionsSuccess {
+                request_id: 9,
+                supported_extensions: vec![3, 2],
+            })
+        );
+
+        let (reply, negotiated) = registry.answer(&RequestExtensions {
+            request_id: 10,
+            requested_extensions: vec![2, 4],
+        });
+        assert_eq!(negotiated, [2]);
+        assert_eq!(
+            reply.decode(),
+            Some(RequestExtensionsError {
+                request_id: 10,
+                unsupported_extensions: vec![4],
+                required_extensions: vec![3],
+            })
+        );
+    }
+}
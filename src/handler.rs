@@ -0,0 +1,746 @@
+use roles_logic_sv2::{
+    common_messages_sv2::{self, Protocol},
+    job_declaration_sv2, mining_sv2,
+    parsers::{CommonMessages, JobDeclaration, Mining, PoolMessages, TemplateDistribution},
+    template_distribution_sv2,
+};
+use std::{
+    collections::HashMap, future::Future, pin::Pin, sync::Mutex as StdMutex, time::SystemTime,
+};
+
+use crate::extension::{ExtensionNegotiator, RawHandler};
+use crate::message_channel::{
+    message_from_frame, message_type, ConnectionId, FrameError, HandlerKey, MessageChannel,
+    MessageChannelError, MessageType, Outcome, Remote,
+};
+use crate::Frame_;
+
+/// What a `Handler` answers to a message.
+#[derive(Clone, Debug)]
+pub enum Reply {
+    /// Nothing to send back, a `Proxy` forwards the message.
+    PassThrough,
+    /// Send the messages back to the remote in order, a `Proxy` forwards them in place of the
+    /// received one.
+    Send(Vec<PoolMessages<'static>>),
+}
+
+/// A message that can be received with a typed handler, the handler is registered for
+/// `MESSAGE_TYPE` and receives the messages as `Self` instead of `PoolMessages`.
+pub trait TypedMessage: Sized + Send + 'static {
+    const MESSAGE_TYPE: MessageType;
+
+    fn from_message(message: PoolMessages<'static>) -> Option<Self>;
+}
+
+macro_rules! handler {
+    ($($method:ident => $pool:ident($parser:ident::$variant:ident($ty:ty)) = $message_type:ident,)*) => {
+        /// Handle the messages received from a remote with one method per message type, instead
+        /// of a pair of channels per message type. Every method defaults to
+        /// `Reply::PassThrough`, so only the messages of interest need to be implemented:
+        ///
+        /// ```ignore
+        /// struct Jobs;
+        ///
+        /// impl Handler for Jobs {
+        ///     async fn on_new_extended_mining_job(
+        ///         &mut self,
+        ///         message: NewExtendedMiningJob<'static>,
+        ///     ) -> Reply {
+        ///         println!("New job {}", message.job_id);
+        ///         Reply::PassThrough
+        ///     }
+        /// }
+        /// ```
+        ///
+        /// Handlers run one message at a time, the next frame is read from the remote after the
+        /// handler returns.
+        pub trait Handler: Send + 'static {
+            $(
+                #[doc = concat!("Called for every `", stringify!($variant), "` received.")]
+                fn $method(&mut self, message: $ty) -> impl Future<Output = Reply> + Send {
+                    let _ = message;
+                    async { Reply::PassThrough }
+                }
+            )*
+        }
+
+        async fn dispatch<H: Handler>(handler: &mut H, message: PoolMessages<'static>) -> Reply {
+            match message {
+                $(PoolMessages::$pool($parser::$variant(m)) => handler.$method(m).await,)*
+            }
+        }
+
+        pub(crate) fn is_known_message_type(message_type: MessageType) -> bool {
+            matches!(message_type, $(const_sv2::$message_type)|*)
+        }
+
+        const KNOWN_MESSAGE_TYPES: &[MessageType] = &[$(const_sv2::$message_type,)*];
+
+        $(
+            impl TypedMessage for $ty {
+                const MESSAGE_TYPE: MessageType = const_sv2::$message_type;
+
+                fn from_message(message: PoolMessages<'static>) -> Option<Self> {
+                    match message {
+                        PoolMessages::$pool($parser::$variant(m)) => Some(m),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+handler! {
+    on_channel_endpoint_changed => Common(CommonMessages::ChannelEndpointChanged(common_messages_sv2::ChannelEndpointChanged)) = MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED,
+    on_setup_connection => Common(CommonMessages::SetupConnection(common_messages_sv2::SetupConnection<'static>)) = MESSAGE_TYPE_SETUP_CONNECTION,
+    on_setup_connection_error => Common(CommonMessages::SetupConnectionError(common_messages_sv2::SetupConnectionError<'static>)) = MESSAGE_TYPE_SETUP_CONNECTION_ERROR,
+    on_setup_connection_success => Common(CommonMessages::SetupConnectionSuccess(common_messages_sv2::SetupConnectionSuccess)) = MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+    on_close_channel => Mining(Mining::CloseChannel(mining_sv2::CloseChannel<'static>)) = MESSAGE_TYPE_CLOSE_CHANNEL,
+    on_new_extended_mining_job => Mining(Mining::NewExtendedMiningJob(mining_sv2::NewExtendedMiningJob<'static>)) = MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+    on_new_mining_job => Mining(Mining::NewMiningJob(mining_sv2::NewMiningJob<'static>)) = MESSAGE_TYPE_NEW_MINING_JOB,
+    on_open_extended_mining_channel => Mining(Mining::OpenExtendedMiningChannel(mining_sv2::OpenExtendedMiningChannel<'static>)) = MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL,
+    on_open_extended_mining_channel_success => Mining(Mining::OpenExtendedMiningChannelSuccess(mining_sv2::OpenExtendedMiningChannelSuccess<'static>)) = MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+    on_open_mining_channel_error => Mining(Mining::OpenMiningChannelError(mining_sv2::OpenMiningChannelError<'static>)) = MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR,
+    on_open_standard_mining_channel => Mining(Mining::OpenStandardMiningChannel(mining_sv2::OpenStandardMiningChannel<'static>)) = MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL,
+    on_open_standard_mining_channel_success => Mining(Mining::OpenStandardMiningChannelSuccess(mining_sv2::OpenStandardMiningChannelSuccess<'static>)) = MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+    on_reconnect => Mining(Mining::Reconnect(mining_sv2::Reconnect<'static>)) = MESSAGE_TYPE_RECONNECT,
+    on_set_custom_mining_job => Mining(Mining::SetCustomMiningJob(mining_sv2::SetCustomMiningJob<'static>)) = MESSAGE_TYPE_SET_CUSTOM_MINING_JOB,
+    on_set_custom_mining_job_error => Mining(Mining::SetCustomMiningJobError(mining_sv2::SetCustomMiningJobError<'static>)) = MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR,
+    on_set_custom_mining_job_success => Mining(Mining::SetCustomMiningJobSuccess(mining_sv2::SetCustomMiningJobSuccess)) = MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS,
+    on_set_extranonce_prefix => Mining(Mining::SetExtranoncePrefix(mining_sv2::SetExtranoncePrefix<'static>)) = MESSAGE_TYPE_SET_EXTRANONCE_PREFIX,
+    on_set_group_channel => Mining(Mining::SetGroupChannel(mining_sv2::SetGroupChannel<'static>)) = MESSAGE_TYPE_SET_GROUP_CHANNEL,
+    on_mining_set_new_prev_hash => Mining(Mining::SetNewPrevHash(mining_sv2::SetNewPrevHash<'static>)) = MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+    on_set_target => Mining(Mining::SetTarget(mining_sv2::SetTarget<'static>)) = MESSAGE_TYPE_SET_TARGET,
+    on_submit_shares_error => Mining(Mining::SubmitSharesError(mining_sv2::SubmitSharesError<'static>)) = MESSAGE_TYPE_SUBMIT_SHARES_ERROR,
+    on_submit_shares_extended => Mining(Mining::SubmitSharesExtended(mining_sv2::SubmitSharesExtended<'static>)) = MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED,
+    on_submit_shares_standard => Mining(Mining::SubmitSharesStandard(mining_sv2::SubmitSharesStandard)) = MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+    on_submit_shares_success => Mining(Mining::SubmitSharesSuccess(mining_sv2::SubmitSharesSuccess)) = MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+    on_update_channel => Mining(Mining::UpdateChannel(mining_sv2::UpdateChannel<'static>)) = MESSAGE_TYPE_UPDATE_CHANNEL,
+    on_update_channel_error => Mining(Mining::UpdateChannelError(mining_sv2::UpdateChannelError<'static>)) = MESSAGE_TYPE_UPDATE_CHANNEL_ERROR,
+    on_allocate_mining_job_token => JobDeclaration(JobDeclaration::AllocateMiningJobToken(job_declaration_sv2::AllocateMiningJobToken<'static>)) = MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN,
+    on_allocate_mining_job_token_success => JobDeclaration(JobDeclaration::AllocateMiningJobTokenSuccess(job_declaration_sv2::AllocateMiningJobTokenSuccess<'static>)) = MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS,
+    on_declare_mining_job => JobDeclaration(JobDeclaration::DeclareMiningJob(job_declaration_sv2::DeclareMiningJob<'static>)) = MESSAGE_TYPE_DECLARE_MINING_JOB,
+    on_declare_mining_job_error => JobDeclaration(JobDeclaration::DeclareMiningJobError(job_declaration_sv2::DeclareMiningJobError<'static>)) = MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
+    on_declare_mining_job_success => JobDeclaration(JobDeclaration::DeclareMiningJobSuccess(job_declaration_sv2::DeclareMiningJobSuccess<'static>)) = MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
+    on_identify_transactions => JobDeclaration(JobDeclaration::IdentifyTransactions(job_declaration_sv2::IdentifyTransactions)) = MESSAGE_TYPE_IDENTIFY_TRANSACTIONS,
+    on_identify_transactions_success => JobDeclaration(JobDeclaration::IdentifyTransactionsSuccess(job_declaration_sv2::IdentifyTransactionsSuccess<'static>)) = MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS,
+    on_provide_missing_transactions => JobDeclaration(JobDeclaration::ProvideMissingTransactions(job_declaration_sv2::ProvideMissingTransactions<'static>)) = MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS,
+    on_provide_missing_transactions_success => JobDeclaration(JobDeclaration::ProvideMissingTransactionsSuccess(job_declaration_sv2::ProvideMissingTransactionsSuccess<'static>)) = MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS,
+    on_submit_solution_jd => JobDeclaration(JobDeclaration::SubmitSolution(job_declaration_sv2::SubmitSolutionJd<'static>)) = MESSAGE_TYPE_SUBMIT_SOLUTION_JD,
+    on_coinbase_output_data_size => TemplateDistribution(TemplateDistribution::CoinbaseOutputDataSize(template_distribution_sv2::CoinbaseOutputDataSize)) = MESSAGE_TYPE_COINBASE_OUTPUT_DATA_SIZE,
+    on_new_template => TemplateDistribution(TemplateDistribution::NewTemplate(template_distribution_sv2::NewTemplate<'static>)) = MESSAGE_TYPE_NEW_TEMPLATE,
+    on_request_transaction_data => TemplateDistribution(TemplateDistribution::RequestTransactionData(template_distribution_sv2::RequestTransactionData)) = MESSAGE_TYPE_REQUEST_TRANSACTION_DATA,
+    on_request_transaction_data_error => TemplateDistribution(TemplateDistribution::RequestTransactionDataError(template_distribution_sv2::RequestTransactionDataError<'static>)) = MESSAGE_TYPE_REQUEST_TRANSACTION_DATA_ERROR,
+    on_request_transaction_data_success => TemplateDistribution(TemplateDistribution::RequestTransactionDataSuccess(template_distribution_sv2::RequestTransactionDataSuccess<'static>)) = MESSAGE_TYPE_REQUEST_TRANSACTION_DATA_SUCCESS,
+    on_set_new_prev_hash => TemplateDistribution(TemplateDistribution::SetNewPrevHash(template_distribution_sv2::SetNewPrevHash<'static>)) = MESSAGE_TYPE_SET_NEW_PREV_HASH,
+    on_submit_solution => TemplateDistribution(TemplateDistribution::SubmitSolution(template_distribution_sv2::SubmitSolution<'static>)) = MESSAGE_TYPE_SUBMIT_SOLUTION,
+}
+
+/// Object safe version of `Handler`, so that handlers of different types can be stored together.
+trait DynHandler: Send {
+    fn handle(
+        &mut self,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Reply> + Send + '_>>;
+}
+
+impl<H: Handler> DynHandler for H {
+    fn handle(
+        &mut self,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Reply> + Send + '_>> {
+        Box::pin(dispatch(self, message))
+    }
+}
+
+/// A `Handler` registered on a `Client`, a `Server` or a `Proxy`.
+pub(crate) struct MessageHandler {
+    pub expect_from: Remote,
+    // Only accessed with `get_mut`, the mutex makes the handler `Sync` without requiring it from
+    // the user
+    handler: StdMutex<Box<dyn DynHandler>>,
+    // Keys owned by a channel handler, the messages that match them are not for this handler
+    owned_by_channels: Vec<HandlerKey>,
+}
+
+impl MessageHandler {
+    pub(crate) fn new(expect_from: Remote, handler: impl Handler) -> Self {
+        Self {
+            expect_from,
+            handler: StdMutex::new(Box::new(handler)),
+            owned_by_channels: vec![],
+        }
+    }
+
+    /// Whether the message is for this handler and not for a channel handler.
+    fn handles(&self, protocol: Option<Protocol>, message_type: MessageType) -> bool {
+        is_known_message_type(message_type)
+            && !self
+                .owned_by_channels
+                .iter()
+                .any(|k| k.matches(protocol, message_type))
+    }
+
+    /// Same as `MessageChannel::on_message`, the replies go to the connection that sent the
+    /// message.
+    pub(crate) async fn on_message(
+        &mut self,
+        id: ConnectionId,
+        message: PoolMessages<'static>,
+    ) -> Result<Outcome, MessageChannelError> {
+        let handler = self
+            .handler
+            .get_mut()
+            .expect("MessageHandler mutex poisoned");
+        match handler.handle(message).await {
+            Reply::PassThrough => Ok(Outcome::Observed),
+            Reply::Send(messages) => Ok(Outcome::Replies(
+                messages.into_iter().map(|message| (id, message)).collect(),
+            )),
+        }
+    }
+}
+
+/// The handlers of a `Client`, a `Server` or a `Proxy`.
+///
+/// Observers only see the messages: every observer registered for a message receives it, in the
+/// order they have been registered. Owners may reply to, or transform, the messages: a message
+/// has at most one owner and the owner receives it after the observers. A `Handler` is the owner
+/// of the messages that have no owner between the channel handlers.
+pub(crate) enum AnyHandler {
+    Channel(MessageChannel),
+    Handler(MessageHandler),
+    Raw(RawHandler),
+    /// Answers the extensions negotiation of the downstreams of a `Server`
+    Extensions(ExtensionNegotiator),
+}
+
+impl AnyHandler {
+    pub(crate) fn expect_from(&self) -> Remote {
+        match self {
+            Self::Channel(channel) => channel.expect_from,
+            Self::Handler(handler) => handler.expect_from,
+            Self::Raw(handler) => handler.expect_from,
+            Self::Extensions(_) => Remote::Client,
+        }
+    }
+
+    fn is_owner(&self) -> bool {
+        match self {
+            Self::Channel(channel) => channel.receiver.is_some(),
+            Self::Handler(_) => true,
+            Self::Raw(_) => false,
+            Self::Extensions(_) => true,
+        }
+    }
+
+    fn conflict_with(&self, other: &Self) -> Option<OwnerConflict> {
+        if self.expect_from() != other.expect_from() || !self.is_owner() || !other.is_owner() {
+            return None;
+        }
+        match (self, other) {
+            (Self::Channel(a), Self::Channel(b)) if a.key.overlaps(&b.key) => {
+                Some(OwnerConflict::Key(a.key))
+            }
+            (Self::Handler(_), Self::Handler(_)) => Some(OwnerConflict::MessageHandler),
+            _ => None,
+        }
+    }
+
+    /// Whether the handler receives the decoded messages of this type, the handlers of the
+    /// extensions see the frames instead.
+    fn handles(&self, protocol: Option<Protocol>, message_type: MessageType) -> bool {
+        match self {
+            Self::Channel(channel) => channel.key.matches(protocol, message_type),
+            Self::Handler(handler) => handler.handles(protocol, message_type),
+            Self::Raw(_) | Self::Extensions(_) => false,
+        }
+    }
+
+    async fn on_message(
+        &mut self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message_type: MessageType,
+        message: PoolMessages<'static>,
+    ) -> Result<Outcome, MessageChannelError> {
+        match self {
+            Self::Channel(channel) => {
+                channel
+                    .on_message(id, received_at, message_type, message)
+                    .await
+            }
+            Self::Handler(handler) => handler.on_message(id, message).await,
+            Self::Raw(_) | Self::Extensions(_) => Ok(Outcome::Observed),
+        }
+    }
+
+    async fn on_frame(
+        &mut self,
+        id: ConnectionId,
+        frame: &mut Frame_,
+    ) -> Result<Outcome, MessageChannelError> {
+        match self {
+            Self::Raw(handler) => {
+                handler.on_frame(id, frame).await?;
+                Ok(Outcome::Observed)
+            }
+            Self::Extensions(negotiator) => negotiator.on_frame(id, frame).await,
+            Self::Channel(_) | Self::Handler(_) => Ok(Outcome::Observed),
+        }
+    }
+}
+
+impl From<MessageChannel> for AnyHandler {
+    fn from(value: MessageChannel) -> Self {
+        Self::Channel(value)
+    }
+}
+
+impl From<RawHandler> for AnyHandler {
+    fn from(value: RawHandler) -> Self {
+        Self::Raw(value)
+    }
+}
+
+impl From<ExtensionNegotiator> for AnyHandler {
+    fn from(value: ExtensionNegotiator) -> Self {
+        Self::Extensions(value)
+    }
+}
+
+impl From<MessageHandler> for AnyHandler {
+    fn from(value: MessageHandler) -> Self {
+        Self::Handler(value)
+    }
+}
+
+/// Why a handler can not be registered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OwnerConflict {
+    /// Another handler with a reply is registered for the key.
+    Key(HandlerKey),
+    /// Another `Handler` is registered.
+    MessageHandler,
+}
+
+/// Register `handler`, fails if it would own messages that are already owned.
+pub(crate) fn push_handler(
+    handlers: &mut Vec<AnyHandler>,
+    handler: impl Into<AnyHandler>,
+) -> Result<(), OwnerConflict> {
+    let handler = handler.into();
+    if let Some(conflict) = handlers.iter().find_map(|h| handler.conflict_with(h)) {
+        eprintln!("Handler not registered, conflicting owner: {conflict:?}");
+        return Err(conflict);
+    }
+    handlers.push(handler);
+    Ok(())
+}
+
+/// The handlers of a `Client`, a `Server` or one direction of a `Proxy`, indexed by the message
+/// type they receive. A frame is only decoded if one of the handlers receives its message type,
+/// and at most once whatever the number of handlers.
+pub(crate) struct Dispatcher {
+    expect_from: Remote,
+    handlers: Vec<AnyHandler>,
+    /// Indexes of the handlers of each message type, in dispatch order
+    by_message_type: HashMap<MessageType, Vec<usize>>,
+    /// Indexes of the handlers that see the frames instead of the messages
+    raw: Vec<usize>,
+}
+
+impl Dispatcher {
+    /// Put the observers before the owners, and tell the `Handler`s which messages are owned by
+    /// the channel handlers. Every handler must expect messages from `expect_from`.
+    pub(crate) fn new(expect_from: Remote, handlers: Vec<AnyHandler>) -> Self {
+        let owned_by_channels: Vec<HandlerKey> = handlers
+            .iter()
+            .filter_map(|handler| match handler {
+                AnyHandler::Channel(channel) if channel.receiver.is_some() => Some(channel.key),
+                _ => None,
+            })
+            .collect();
+        let (observers, mut owners): (Vec<_>, Vec<_>) = handlers
+            .into_iter()
+            .partition(|handler| !handler.is_owner());
+        for owner in owners.iter_mut() {
+            if let AnyHandler::Handler(handler) = owner {
+                handler.owned_by_channels = owned_by_channels.clone();
+            }
+        }
+        let handlers: Vec<AnyHandler> = observers.into_iter().chain(owners).collect();
+        let mut by_message_type: HashMap<MessageType, Vec<usize>> = HashMap::new();
+        let mut raw = vec![];
+        for (index, handler) in handlers.iter().enumerate() {
+            let message_types = match handler {
+                AnyHandler::Channel(channel) => std::slice::from_ref(&channel.key.message_type),
+                AnyHandler::Handler(_) => KNOWN_MESSAGE_TYPES,
+                AnyHandler::Raw(_) | AnyHandler::Extensions(_) => {
+                    raw.push(index);
+                    continue;
+                }
+            };
+            for message_type in message_types {
+                by_message_type
+                    .entry(*message_type)
+                    .or_default()
+                    .push(index);
+            }
+        }
+        Self {
+            expect_from,
+            handlers,
+            by_message_type,
+            raw,
+        }
+    }
+
+    /// Run the handlers on `frame`, read from the remote at `received_at`, the outcome of each
+    /// handler is returned by `Dispatch::next`.
+    pub(crate) fn dispatch<'a>(
+        &'a mut self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        protocol: Option<Protocol>,
+        frame: &'a mut Frame_,
+    ) -> Dispatch<'a> {
+        let (message_type, decoded, invalid_header) = match message_type(frame) {
+            Ok(Some(mt)) => {
+                let decoded = self
+                    .by_message_type
+                    .get(&mt)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                (mt, decoded, false)
+            }
+            Ok(None) => (0, &[][..], false),
+            // Reported if some handler would decode the frame
+            Err(_) => (0, &[][..], !self.by_message_type.is_empty()),
+        };
+        let receivers = decoded
+            .iter()
+            .filter(|index| self.handlers[**index].handles(protocol, message_type))
+            .count();
+        Dispatch {
+            id,
+            received_at,
+            protocol,
+            expect_from: self.expect_from,
+            frame,
+            handlers: &mut self.handlers,
+            message_type,
+            decoded,
+            raw: &self.raw,
+            receivers,
+            message: None,
+            invalid_header,
+        }
+    }
+}
+
+/// The dispatch of a frame to the handlers, one handler at a time.
+pub(crate) struct Dispatch<'a> {
+    id: ConnectionId,
+    received_at: SystemTime,
+    protocol: Option<Protocol>,
+    expect_from: Remote,
+    frame: &'a mut Frame_,
+    handlers: &'a mut [AnyHandler],
+    message_type: MessageType,
+    /// The handlers of the message type that have not run yet
+    decoded: &'a [usize],
+    /// The handlers of the frames that have not run yet
+    raw: &'a [usize],
+    /// How many of the handlers left receive the message, the last one takes the decoded message
+    /// and the others a clone
+    receivers: usize,
+    message: Option<PoolMessages<'static>>,
+    invalid_header: bool,
+}
+
+impl Dispatch<'_> {
+    /// Run the next handler, `None` when every handler has run.
+    pub(crate) async fn next(&mut self) -> Option<Result<Outcome, MessageChannelError>> {
+        if std::mem::take(&mut self.invalid_header) {
+            return Some(self.decode().map(|_| Outcome::Observed));
+        }
+        loop {
+            let (index, is_raw) = match (self.decoded.first(), self.raw.first()) {
+                (Some(decoded), Some(raw)) if raw < decoded => (*raw, true),
+                (Some(decoded), _) => (*decoded, false),
+                (None, Some(raw)) => (*raw, true),
+                (None, None) => return None,
+            };
+            if is_raw {
+                self.raw = &self.raw[1..];
+                return Some(self.handlers[index].on_frame(self.id, self.frame).await);
+            }
+            self.decoded = &self.decoded[1..];
+            if !self.handlers[index].handles(self.protocol, self.message_type) {
+                continue;
+            }
+            let message = match self.decode() {
+                Ok(message) => message,
+                Err(e) => return Some(Err(e)),
+            };
+            self.receivers -= 1;
+            if self.receivers > 0 {
+                self.message = Some(message.clone());
+            }
+            let outcome = self.handlers[index]
+                .on_message(self.id, self.received_at, self.message_type, message)
+                .await;
+            return Some(outcome);
+        }
+    }
+
+    /// The message of a frame for which a response to a request is expected. The message is
+    /// decoded only for the known message types, and must be given back with `put_message` if it
+    /// is not a response. An invalid frame must be reported like the errors of `next`, no
+    /// handler runs after it.
+    pub(crate) fn take_message(&mut self) -> Result<Option<PoolMessages<'static>>, FrameError> {
+        match message_type(self.frame) {
+            Ok(Some(mt)) if is_known_message_type(mt) => self.decode_frame().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn put_message(&mut self, message: PoolMessages<'static>) {
+        self.message = Some(message);
+    }
+
+    /// Take the decoded message, decoding the frame if it has not been decoded yet. After an
+    /// invalid frame no other handler runs.
+    fn decode(&mut self) -> Result<PoolMessages<'static>, MessageChannelError> {
+        Ok(self.decode_frame()?)
+    }
+
+    fn decode_frame(&mut self) -> Result<PoolMessages<'static>, FrameError> {
+        if let Some(message) = self.message.take() {
+            return Ok(message);
+        }
+        match message_from_frame(self.frame, self.expect_from, self.protocol) {
+            Ok((_, message)) => Ok(message),
+            Err(e) => {
+                self.decoded = &[];
+                self.raw = &[];
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_channel::{HandlerReceiver, HandlerSender, Overflow};
+    use const_sv2::{MESSAGE_TYPE_CLOSE_CHANNEL, MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS};
+    use std::sync::Arc;
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+    const PROTOCOL: Option<Protocol> = Some(Protocol::MiningProtocol);
+
+    fn observer(message_type: MessageType) -> (AnyHandler, Receiver<PoolMessages<'static>>) {
+        let (sender, receiver) = channel(3);
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Server,
+            receiver: None,
+            sender: HandlerSender::Plain(sender),
+            overflow: Overflow::default(),
+        };
+        (channel.into(), receiver)
+    }
+
+    fn owner(
+        message_type: MessageType,
+    ) -> (
+        AnyHandler,
+        Receiver<PoolMessages<'static>>,
+        Sender<PoolMessages<'static>>,
+    ) {
+        let (sender, receiver) = channel(3);
+        let (reply_sender, reply_receiver) = channel(3);
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Server,
+            receiver: Some(HandlerReceiver::Plain(reply_receiver)),
+            sender: HandlerSender::Plain(sender),
+            overflow: Overflow::default(),
+        };
+        (channel.into(), receiver, reply_sender)
+    }
+
+    /// Records the message types it handles.
+    struct Recorder(Arc<StdMutex<Vec<MessageType>>>);
+
+    impl Handler for Recorder {
+        async fn on_submit_shares_success(
+            &mut self,
+            _message: mining_sv2::SubmitSharesSuccess,
+        ) -> Reply {
+            self.0
+                .lock()
+                .unwrap()
+                .push(const_sv2::MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS);
+            Reply::PassThrough
+        }
+
+        async fn on_close_channel(&mut self, _message: mining_sv2::CloseChannel<'static>) -> Reply {
+            self.0
+                .lock()
+                .unwrap()
+                .push(const_sv2::MESSAGE_TYPE_CLOSE_CHANNEL);
+            Reply::PassThrough
+        }
+    }
+
+    /// The frame of `message` as read from a remote.
+    fn received(message: PoolMessages<'static>) -> Frame_ {
+        let frame: crate::StdFrame = message.try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        crate::StdFrame::from_bytes(bytes.into()).unwrap().into()
+    }
+
+    fn submit_shares_success() -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::SubmitSharesSuccess(
+            mining_sv2::SubmitSharesSuccess {
+                channel_id: 1,
+                last_sequence_number: 2,
+                new_submits_accepted_count: 3,
+                new_shares_sum: 4,
+            },
+        ))
+    }
+
+    fn close_channel() -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::CloseChannel(mining_sv2::CloseChannel {
+            channel_id: 1,
+            reason_code: "".to_string().try_into().unwrap(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn observers_run_before_the_owner() {
+        let (owner, mut owned, reply) = owner(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS);
+        let (first, mut first_received) = observer(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS);
+        let (second, mut second_received) = observer(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS);
+        let (other, mut other_received) = observer(MESSAGE_TYPE_CLOSE_CHANNEL);
+        let mut dispatcher = Dispatcher::new(Remote::Server, vec![owner, first, other, second]);
+        reply.send(close_channel()).await.unwrap();
+
+        let mut frame = received(submit_shares_success());
+        let mut dispatch = dispatcher.dispatch(0, SystemTime::now(), PROTOCOL, &mut frame);
+        assert!(matches!(dispatch.next().await, Some(Ok(Outcome::Observed))));
+        assert!(first_received.try_recv().is_ok());
+        assert!(second_received.try_recv().is_err());
+        assert!(matches!(dispatch.next().await, Some(Ok(Outcome::Observed))));
+        assert!(second_received.try_recv().is_ok());
+        assert!(owned.try_recv().is_err());
+        match dispatch.next().await {
+            Some(Ok(Outcome::Replies(replies))) => {
+                assert_eq!(replies.len(), 1);
+                assert!(matches!(
+                    replies[0],
+                    (0, PoolMessages::Mining(Mining::CloseChannel(_)))
+                ));
+            }
+            _ => panic!("The owner should reply"),
+        }
+        assert!(owned.try_recv().is_ok());
+        assert!(dispatch.next().await.is_none());
+        assert!(other_received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn handler_only_owns_the_messages_without_owner() {
+        let handled = Arc::new(StdMutex::new(vec![]));
+        let handler = MessageHandler::new(Remote::Server, Recorder(handled.clone()));
+        let (owner, mut owned, reply) = owner(MESSAGE_TYPE_CLOSE_CHANNEL);
+        let (observer, mut observed) = observer(MESSAGE_TYPE_CLOSE_CHANNEL);
+        let mut dispatcher = Dispatcher::new(Remote::Server, vec![handler.into(), owner, observer]);
+        reply.send(submit_shares_success()).await.unwrap();
+
+        for message in [close_channel(), submit_shares_success()] {
+            let mut frame = received(message);
+            let mut dispatch = dispatcher.dispatch(0, SystemTime::now(), PROTOCOL, &mut frame);
+            while let Some(outcome) = dispatch.next().await {
+                assert!(outcome.is_ok());
+            }
+        }
+        assert!(observed.try_recv().is_ok());
+        assert!(owned.try_recv().is_ok());
+        assert_eq!(
+            *handled.lock().unwrap(),
+            [MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_handler_runs_after_an_invalid_frame() {
+        let (first, mut first_received) = observer(MESSAGE_TYPE_CLOSE_CHANNEL);
+        let (second, mut second_received) = observer(MESSAGE_TYPE_CLOSE_CHANNEL);
+        let mut dispatcher = Dispatcher::new(Remote::Server, vec![first, second]);
+        // The reason code is shorter than its length
+        let bytes = vec![
+            0,
+            0,
+            MESSAGE_TYPE_CLOSE_CHANNEL,
+            6,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            5,
+            b'a',
+        ];
+        let mut frame: Frame_ = crate::StdFrame::from_bytes(bytes.into()).unwrap().into();
+
+        let mut dispatch = dispatcher.dispatch(0, SystemTime::now(), PROTOCOL, &mut frame);
+        assert!(matches!(
+            dispatch.next().await,
+            Some(Err(MessageChannelError::InvalidFrame(
+                FrameError::InvalidPayload(MESSAGE_TYPE_CLOSE_CHANNEL)
+            )))
+        ));
+        assert!(dispatch.next().await.is_none());
+        assert!(first_received.try_recv().is_err());
+        assert!(second_received.try_recv().is_err());
+    }
+
+    #[test]
+    fn owners_can_not_overlap() {
+        let mut handlers = vec![];
+        push_handler(&mut handlers, observer(MESSAGE_TYPE_CLOSE_CHANNEL).0).unwrap();
+        push_handler(&mut handlers, owner(MESSAGE_TYPE_CLOSE_CHANNEL).0).unwrap();
+        assert_eq!(
+            push_handler(&mut handlers, owner(MESSAGE_TYPE_CLOSE_CHANNEL).0),
+            Err(OwnerConflict::Key(MESSAGE_TYPE_CLOSE_CHANNEL.into()))
+        );
+        push_handler(
+            &mut handlers,
+            MessageHandler::new(Remote::Server, Recorder(Default::default())),
+        )
+        .unwrap();
+        assert_eq!(
+            push_handler(
+                &mut handlers,
+                MessageHandler::new(Remote::Server, Recorder(Default::default())),
+            ),
+            Err(OwnerConflict::MessageHandler)
+        );
+        // The handlers of the other remote are not in conflict
+        push_handler(
+            &mut handlers,
+            MessageHandler::new(Remote::Client, Recorder(Default::default())),
+        )
+        .unwrap();
+        assert_eq!(handlers.len(), 4);
+    }
+}
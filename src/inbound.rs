@@ -0,0 +1,152 @@
+use roles_logic_sv2::{
+    common_messages_sv2::{SetupConnection, SetupConnectionSuccess},
+    parsers::PoolMessages,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    time::SystemTime,
+};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::{
+    authorization::Identities,
+    message_channel::{map_try_send_error, ConnectionId, TypedSender},
+};
+
+/// A message received by an inbound handler, with the connection it comes from.
+#[derive(Clone, Debug)]
+pub struct Inbound {
+    /// The downstream that sent the message for a `Server`, always 0 for a `Client` and a `Proxy`
+    pub conn_id: ConnectionId,
+    /// `None` when the remote is not connected with TCP
+    pub peer_addr: Option<SocketAddr>,
+    /// When the frame of the message has been read from the remote
+    pub received_at: SystemTime,
+    /// The SetupConnection of the connection: the one received from the downstream for a
+    /// `Server` and a `Proxy`, the one sent upstream for a `Client`. A `Proxy` only knows it once
+    /// the downstream has sent it.
+    pub setup: Option<SetupConnection<'static>>,
+    /// The version negotiated in the SetupConnection.Success, `None` until it is known like
+    /// `setup`
+    pub used_version: Option<u16>,
+    /// The flags of the SetupConnection.Success
+    pub flags: Option<u32>,
+    /// The identity given by the authorizer of a `Server` to the channel of the message, or else
+    /// to the connection, see `Authorization::AcceptAs`
+    pub identity: Option<String>,
+    pub msg: PoolMessages<'static>,
+}
+
+#[derive(Default)]
+struct Origin {
+    address: Option<SocketAddr>,
+    setup: Option<SetupConnection<'static>>,
+    success: Option<SetupConnectionSuccess>,
+}
+
+/// Address, SetupConnection and identities of the connections of a `Client`, a `Server` or one
+/// side of a `Proxy`, shared with the inbound handlers.
+#[derive(Clone, Default)]
+pub(crate) struct Origins {
+    origins: Arc<StdMutex<HashMap<ConnectionId, Origin>>>,
+    /// Only given by the authorizer of a `Server`
+    identities: Identities,
+}
+
+impl Origins {
+    pub(crate) fn set_address(&self, connection: ConnectionId, address: Option<SocketAddr>) {
+        self.origins
+            .lock()
+            .expect("Origins mutex poisoned")
+            .entry(connection)
+            .or_default()
+            .address = address;
+    }
+
+    pub(crate) fn set_setup(&self, connection: ConnectionId, setup: SetupConnection<'static>) {
+        self.origins
+            .lock()
+            .expect("Origins mutex poisoned")
+            .entry(connection)
+            .or_default()
+            .setup = Some(setup);
+    }
+
+    pub(crate) fn set_success(&self, connection: ConnectionId, success: SetupConnectionSuccess) {
+        self.origins
+            .lock()
+            .expect("Origins mutex poisoned")
+            .entry(connection)
+            .or_default()
+            .success = Some(success);
+    }
+
+    pub(crate) fn remove(&self, connection: ConnectionId) {
+        self.origins
+            .lock()
+            .expect("Origins mutex poisoned")
+            .remove(&connection);
+    }
+
+    pub(crate) fn identities(&self) -> &Identities {
+        &self.identities
+    }
+
+    fn inbound(
+        &self,
+        connection: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Inbound {
+        let identity = self.identities.of_message(connection, &message);
+        let origins = self.origins.lock().expect("Origins mutex poisoned");
+        let origin = origins.get(&connection);
+        Inbound {
+            conn_id: connection,
+            peer_addr: origin.and_then(|o| o.address),
+            received_at,
+            setup: origin.and_then(|o| o.setup.clone()),
+            used_version: origin.and_then(|o| o.success).map(|s| s.used_version),
+            flags: origin.and_then(|o| o.success).map(|s| s.flags),
+            identity,
+            msg: message,
+        }
+    }
+}
+
+/// Sender of an inbound handler, wraps the messages in an `Inbound`.
+pub(crate) struct InboundSender {
+    pub(crate) sender: Sender<Inbound>,
+    pub(crate) origins: Origins,
+}
+
+impl TypedSender for InboundSender {
+    fn send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>> {
+        let inbound = self.origins.inbound(id, received_at, message);
+        Box::pin(async move { self.sender.send(inbound).await.map_err(|_| ()) })
+    }
+
+    fn try_send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), TrySendError<()>> {
+        self.sender
+            .try_send(self.origins.inbound(id, received_at, message))
+            .map_err(|e| map_try_send_error(&e))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
@@ -0,0 +1,244 @@
+//! Authority keys used to authenticate servers and proxies during the noise handshake.
+//!
+//! Keys are saved in a text file with one `name = value` entry per line, values are the base58
+//! encodings used by `key-utils`. Empty lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # Stratum V2 authority keys
+//! public_key = 9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72
+//! secret_key = mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n
+//! ```
+//!
+//! `public_key` is optional, when missing it is derived from `secret_key`. On unix the file is
+//! created readable only by its owner, and reading a file that the group or other users can
+//! access fails with `KeysError::InsecurePermissions`.
+use key_utils::{Error as KeyUtilsError, Secp256k1PublicKey, Secp256k1SecretKey};
+use secp256k1::{rand, Keypair, Parity, Secp256k1};
+use std::{fmt::Display, fs::OpenOptions, io::Write, path::Path, str::FromStr};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AuthorityKeyPair {
+    pub public_key: Secp256k1PublicKey,
+    pub secret_key: Secp256k1SecretKey,
+}
+
+#[derive(Debug)]
+pub enum KeysError {
+    Io(std::io::Error),
+    KeyError(KeyUtilsError),
+    /// The key file can be accessed by the group or by other users, contains the file mode.
+    InsecurePermissions(u32),
+    InvalidKeyFile,
+    KeyPairMismatch,
+}
+
+impl AuthorityKeyPair {
+    /// Generate a new random key pair.
+    pub fn generate() -> Self {
+        let secp = Secp256k1::new();
+        loop {
+            let (secret_key, _) = secp.generate_keypair(&mut rand::thread_rng());
+            let key_pair = Keypair::from_secret_key(&secp, &secret_key);
+            // Same as the key-utils generator: only keys with an even y coordinate are used
+            if key_pair.x_only_public_key().1 == Parity::Even {
+                return Self::from_secret_key(Secp256k1SecretKey(secret_key));
+            }
+        }
+    }
+
+    pub fn from_secret_key(secret_key: Secp256k1SecretKey) -> Self {
+        Self {
+            public_key: public_key(secret_key),
+            secret_key,
+        }
+    }
+
+    /// Read a key pair from a key file, see the module documentation for the format.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, KeysError> {
+        let path = path.as_ref();
+        #[cfg(unix)]
+        {
+            let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                eprintln!(
+                    "Key file {} has mode {mode:o}, expected 600",
+                    path.display()
+                );
+                return Err(KeysError::InsecurePermissions(mode));
+            }
+        }
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Write the key pair in a new key file, fail if the file already exists.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), KeysError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        file.write_all(self.to_string().as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Derive the public key of `secret_key`.
+pub fn public_key(secret_key: Secp256k1SecretKey) -> Secp256k1PublicKey {
+    secret_key.into()
+}
+
+impl FromStr for AuthorityKeyPair {
+    type Err = KeysError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut public_key: Option<Secp256k1PublicKey> = None;
+        let mut secret_key: Option<Secp256k1SecretKey> = None;
+        for line in content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                Some(("public_key", value)) if public_key.is_none() => {
+                    public_key = Some(value.parse()?)
+                }
+                Some(("secret_key", value)) if secret_key.is_none() => {
+                    secret_key = Some(value.parse()?)
+                }
+                _ => return Err(KeysError::InvalidKeyFile),
+            }
+        }
+        let key_pair = Self::from_secret_key(secret_key.ok_or(KeysError::InvalidKeyFile)?);
+        match public_key {
+            Some(public_key) if public_key.into_bytes() != key_pair.public_key.into_bytes() => {
+                Err(KeysError::KeyPairMismatch)
+            }
+            _ => Ok(key_pair),
+        }
+    }
+}
+
+impl Display for AuthorityKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# Stratum V2 authority keys")?;
+        writeln!(f, "public_key = {}", self.public_key)?;
+        writeln!(f, "secret_key = {}", self.secret_key)
+    }
+}
+
+impl From<std::io::Error> for KeysError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<KeyUtilsError> for KeysError {
+    fn from(value: KeyUtilsError) -> Self {
+        Self::KeyError(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A path in the temporary directory that does not exist yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.keys", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn display_and_from_str_round_trip() {
+        let key_pair = AuthorityKeyPair::generate();
+        let parsed: AuthorityKeyPair = key_pair.to_string().parse().unwrap();
+        assert_eq!(
+            parsed.public_key.into_bytes(),
+            key_pair.public_key.into_bytes()
+        );
+        assert_eq!(
+            parsed.secret_key.into_bytes(),
+            key_pair.secret_key.into_bytes()
+        );
+    }
+
+    #[test]
+    fn public_key_is_derived_when_missing() {
+        let content =
+            "\n# only the secret\nsecret_key = mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n\n";
+        let key_pair: AuthorityKeyPair = content.parse().unwrap();
+        assert_eq!(
+            key_pair.public_key.to_string(),
+            "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+        );
+    }
+
+    #[test]
+    fn mismatched_public_key_is_refused() {
+        let other = AuthorityKeyPair::generate();
+        let content = format!(
+            "public_key = {}\nsecret_key = mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n\n",
+            other.public_key
+        );
+        assert!(matches!(
+            content.parse::<AuthorityKeyPair>(),
+            Err(KeysError::KeyPairMismatch)
+        ));
+    }
+
+    #[test]
+    fn invalid_key_files_are_refused() {
+        for content in [
+            "",
+            "public_key = 9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72",
+            "secret_key",
+            "private_key = mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n",
+        ] {
+            assert!(
+                matches!(
+                    content.parse::<AuthorityKeyPair>(),
+                    Err(KeysError::InvalidKeyFile)
+                ),
+                "{content:?} should be invalid"
+            );
+        }
+        assert!(matches!(
+            "secret_key = not-base58".parse::<AuthorityKeyPair>(),
+            Err(KeysError::KeyError(_))
+        ));
+    }
+
+    #[test]
+    fn write_then_read() {
+        let path = temp_path("write-then-read");
+        let key_pair = AuthorityKeyPair::generate();
+        key_pair.write(&path).unwrap();
+        assert!(matches!(key_pair.write(&path), Err(KeysError::Io(_))));
+        let read = AuthorityKeyPair::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read.secret_key.into_bytes(),
+            key_pair.secret_key.into_bytes()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_by_others_is_refused() {
+        let path = temp_path("readable-by-others");
+        AuthorityKeyPair::generate().write(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let read = AuthorityKeyPair::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(read, Err(KeysError::InsecurePermissions(0o644))));
+    }
+}
@@ -12,10 +12,33 @@ mod into_static;
 #[cfg(not(feature = "with_serde"))]
 pub(crate) use into_static::into_static;
 
+mod extension;
+pub use extension::{
+    ExtensionMessage, RawFrame, RequestExtensions, RequestExtensionsError,
+    RequestExtensionsSuccess, EXTENSION_TYPE_EXTENSIONS_NEGOTIATION,
+    MESSAGE_TYPE_REQUEST_EXTENSIONS, MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR,
+    MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS,
+};
+mod handler;
+pub use handler::{Handler, Reply, TypedMessage};
+mod authorization;
+pub use authorization::{Authorization, Authorizer, Identities, Peer};
+mod connection;
+mod inbound;
+pub use inbound::Inbound;
 mod message_channel;
-pub use message_channel::Remote;
+pub use connection::{noise_pair, pair, Endpoint};
+mod request;
+pub use message_channel::{
+    ConnectionId, DropCounter, FrameError, HandlerConfig, HandlerKey, InvalidFrame,
+    InvalidFramePolicy, OverflowPolicy, Remote, RoutedMessage, Verdict,
+};
+pub use request::{RequestError, Requester, ServerRequester};
+mod shutdown;
+pub use shutdown::ShutdownHandle;
 
 pub mod client_helpers;
+pub mod keys;
 pub mod proxy_helpers;
 pub mod server_helpers;
 pub use client_helpers::*;
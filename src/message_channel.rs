@@ -1,89 +1,745 @@
+use crate::handler::TypedMessage;
 use crate::into_static;
 use codec_sv2::framing_sv2::framing::Frame as EitherFrame;
 pub use roles_logic_sv2;
 pub use roles_logic_sv2::parsers::PoolMessages;
-use roles_logic_sv2::parsers::TemplateDistribution;
-use tokio::sync::mpsc::{Receiver, Sender};
+use roles_logic_sv2::{
+    common_messages_sv2::Protocol,
+    parsers::{CommonMessageTypes, CommonMessages, JobDeclaration, Mining, TemplateDistribution},
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::SystemTime,
+};
+use tokio::sync::{
+    mpsc::{error::TrySendError, Receiver, Sender},
+    Notify,
+};
 
 pub type MessageType = u8;
+/// Identifies a downstream connection accepted by a listening `Server`.
+pub type ConnectionId = u32;
+pub type RoutedMessage = (ConnectionId, PoolMessages<'static>);
 use crate::Frame_;
 use crate::StdFrame;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Remote {
     Client,
     Server,
 }
 
-pub struct MessageChannel {
+/// Plain handlers only see the message, routed handlers also see the id of the connection that
+/// sent it.
+#[derive(Clone)]
+pub enum HandlerSender {
+    Plain(Sender<PoolMessages<'static>>),
+    Routed(Sender<RoutedMessage>),
+    Typed(Arc<dyn TypedSender>),
+}
+
+/// A routed reply is sent to the connection with the returned id, a plain reply to the connection
+/// that sent the message.
+pub enum HandlerReceiver {
+    Plain(Receiver<PoolMessages<'static>>),
+    Routed(Receiver<RoutedMessage>),
+    Verdict(Receiver<Verdict>),
+}
+
+/// What a `Proxy` does with a message received by a handler with verdict.
+#[derive(Clone, Debug)]
+pub enum Verdict {
+    /// Forward the message unchanged.
+    Forward,
+    /// Do not forward the message.
+    Drop,
+    /// Forward the message in place of the received one.
+    Replace(Box<PoolMessages<'static>>),
+    /// Send each message to its remote, in order, in place of the received one: `Remote::Server`
+    /// for the upstream and `Remote::Client` for the downstream. A message can be answered
+    /// directly while notifying the other side, an empty list is the same as `Drop`.
+    Emit(Vec<(Remote, PoolMessages<'static>)>),
+}
+
+/// What a handler did with a message.
+pub(crate) enum Outcome {
+    /// The handler only saw the message.
+    Observed,
+    /// Messages to send in reply, a `Proxy` forwards them in place of the message.
+    Replies(Vec<(ConnectionId, PoolMessages<'static>)>),
+    /// Frames to send in reply, only returned by the extensions negotiation of a `Server`.
+    Frames(Vec<(ConnectionId, Frame_)>),
+    /// Only returned by the handlers of a `Proxy`.
+    Verdict(Verdict),
+}
+
+impl HandlerSender {
+    async fn send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), ()> {
+        match self {
+            Self::Plain(s) => s.send(message).await.map_err(|_| ()),
+            Self::Routed(s) => s.send((id, message)).await.map_err(|_| ()),
+            Self::Typed(s) => s.send(id, received_at, message).await,
+        }
+    }
+
+    fn try_send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), TrySendError<()>> {
+        match self {
+            Self::Plain(s) => s.try_send(message).map_err(|e| map_try_send_error(&e)),
+            Self::Routed(s) => s
+                .try_send((id, message))
+                .map_err(|e| map_try_send_error(&e)),
+            Self::Typed(s) => s.try_send(id, received_at, message),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Plain(s) => s.is_closed(),
+            Self::Routed(s) => s.is_closed(),
+            Self::Typed(s) => s.is_closed(),
+        }
+    }
+}
+
+/// Sender of a typed handler, converts the messages to the type of the handler. `received_at` is
+/// when the frame of the message has been read from the remote.
+pub trait TypedSender: Send + Sync {
+    fn send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>>;
+
+    fn try_send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), TrySendError<()>>;
+
+    fn is_closed(&self) -> bool;
+}
+
+/// A typed handler that only sees the message.
+pub struct Typed<T>(pub Sender<T>);
+
+/// A typed handler that also sees the id of the connection that sent the message.
+pub struct RoutedTyped<T>(pub Sender<(ConnectionId, T)>);
+
+fn typed<T: TypedMessage>(message: PoolMessages<'static>) -> T {
+    // Message types are unique across the subprotocols, so the key of the handler is enough to
+    // decode only messages of type T
+    T::from_message(message).expect("A typed handler only receives messages of its type")
+}
+
+impl<T: TypedMessage> TypedSender for Typed<T> {
+    fn send(
+        &self,
+        _: ConnectionId,
+        _: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>> {
+        Box::pin(async move { self.0.send(typed(message)).await.map_err(|_| ()) })
+    }
+
+    fn try_send(
+        &self,
+        _: ConnectionId,
+        _: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), TrySendError<()>> {
+        self.0
+            .try_send(typed(message))
+            .map_err(|e| map_try_send_error(&e))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+impl<T: TypedMessage> TypedSender for RoutedTyped<T> {
+    fn send(
+        &self,
+        id: ConnectionId,
+        _: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>> {
+        Box::pin(async move { self.0.send((id, typed(message))).await.map_err(|_| ()) })
+    }
+
+    fn try_send(
+        &self,
+        id: ConnectionId,
+        _: SystemTime,
+        message: PoolMessages<'static>,
+    ) -> Result<(), TrySendError<()>> {
+        self.0
+            .try_send((id, typed(message)))
+            .map_err(|e| map_try_send_error(&e))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+pub(crate) fn map_try_send_error<T>(error: &TrySendError<T>) -> TrySendError<()> {
+    match error {
+        TrySendError::Full(_) => TrySendError::Full(()),
+        TrySendError::Closed(_) => TrySendError::Closed(()),
+    }
+}
+
+/// What to do with a message when the channel of its handler is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait for the handler, this stops the dispatch of the following messages.
+    #[default]
+    Block,
+    /// Drop the oldest message in the channel to make room for the new one.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Drop the new message and close the connection with the peer that sent it. The handlers of
+    /// events, eg `add_invalid_frame_handler_with_config`, only drop it.
+    Disconnect,
+}
+
+/// Channel of a handler that does not reply.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandlerConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl HandlerConfig {
+    /// With `DropOldest` the messages wait in the queue of the `MessageChannel`, the handler
+    /// channel only holds the one being forwarded.
+    pub(crate) fn channel_capacity(&self) -> usize {
+        match self.overflow {
+            OverflowPolicy::DropOldest => 1,
+            _ => self.capacity.max(1),
+        }
+    }
+}
+
+impl Default for HandlerConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 3,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// Number of messages that have been dropped because the channel of a handler was full.
+#[derive(Clone, Debug, Default)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    pub fn dropped(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A message received by a `MessageChannel`, with the connection that sent it and when.
+type Received = (ConnectionId, SystemTime, PoolMessages<'static>);
+
+/// The channel of a handler, the items are messages for a `MessageChannel` and frames or
+/// extension messages for a raw handler.
+pub(crate) trait HandlerChannel<T>: Clone + Send + Sync + 'static {
+    fn send(&self, item: T) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>>;
+    fn try_send(&self, item: T) -> Result<(), TrySendError<()>>;
+    fn is_closed(&self) -> bool;
+}
+
+impl HandlerChannel<Received> for HandlerSender {
+    fn send(
+        &self,
+        (id, received_at, message): Received,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>> {
+        Box::pin(HandlerSender::send(self, id, received_at, message))
+    }
+
+    fn try_send(&self, (id, received_at, message): Received) -> Result<(), TrySendError<()>> {
+        HandlerSender::try_send(self, id, received_at, message)
+    }
+
+    fn is_closed(&self) -> bool {
+        HandlerSender::is_closed(self)
+    }
+}
+
+impl<T: Send + 'static> HandlerChannel<T> for Sender<T> {
+    fn send(&self, item: T) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + '_>> {
+        Box::pin(async move { Sender::send(self, item).await.map_err(|_| ()) })
+    }
+
+    fn try_send(&self, item: T) -> Result<(), TrySendError<()>> {
+        Sender::try_send(self, item).map_err(|e| map_try_send_error(&e))
+    }
+
+    fn is_closed(&self) -> bool {
+        Sender::is_closed(self)
+    }
+}
+
+/// Why an item has not been sent to a handler.
+pub(crate) enum OverflowError {
+    /// The handler has been dropped
+    Closed,
+    /// The channel is full and the policy is `Disconnect`
+    Full,
+}
+
+/// How a handler handles its full channel.
+pub struct Overflow<T = Received> {
+    policy: OverflowPolicy,
+    dropped: DropCounter,
+    oldest_first: Option<Arc<OldestFirst<T>>>,
+}
+
+impl<T> Default for Overflow<T> {
+    fn default() -> Self {
+        Self {
+            policy: OverflowPolicy::default(),
+            dropped: DropCounter::default(),
+            oldest_first: None,
+        }
+    }
+}
+
+impl<T: Send + 'static> Overflow<T> {
+    /// The handler channel must be created with `HandlerConfig::channel_capacity`.
+    pub fn new(config: HandlerConfig, dropped: DropCounter) -> Self {
+        let oldest_first = (config.overflow == OverflowPolicy::DropOldest).then(|| {
+            Arc::new(OldestFirst {
+                messages: StdMutex::new(VecDeque::with_capacity(config.capacity)),
+                capacity: config.capacity,
+                notify: Notify::new(),
+                forwarding: std::sync::Once::new(),
+                closed: AtomicBool::new(false),
+            })
+        });
+        Self {
+            policy: config.overflow,
+            dropped,
+            oldest_first,
+        }
+    }
+
+    /// Send `item` to the handler with `sender` according to the policy.
+    pub(crate) async fn send(
+        &self,
+        sender: &impl HandlerChannel<T>,
+        item: T,
+    ) -> Result<(), OverflowError> {
+        match (&self.oldest_first, self.policy) {
+            (_, OverflowPolicy::Block) => {
+                sender.send(item).await.map_err(|_| OverflowError::Closed)
+            }
+            (Some(oldest_first), _) => {
+                if sender.is_closed() {
+                    return Err(OverflowError::Closed);
+                }
+                if oldest_first.push(item, sender) {
+                    self.dropped.increment();
+                }
+                Ok(())
+            }
+            (None, policy) => match sender.try_send(item) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(())) => {
+                    self.dropped.increment();
+                    if policy == OverflowPolicy::Disconnect {
+                        return Err(OverflowError::Full);
+                    }
+                    Ok(())
+                }
+                Err(TrySendError::Closed(())) => Err(OverflowError::Closed),
+            },
+        }
+    }
+}
+
+impl<T> Drop for Overflow<T> {
+    fn drop(&mut self) {
+        // Let the forwarding task see that there is nothing left to forward, the notification is
+        // kept if the task is not waiting yet
+        if let Some(oldest_first) = &self.oldest_first {
+            oldest_first.closed.store(true, Ordering::Release);
+            oldest_first.notify.notify_one();
+        }
+    }
+}
+
+/// Queue of the items for a `DropOldest` handler, a task forwards them to the handler.
+struct OldestFirst<T> {
+    messages: StdMutex<VecDeque<T>>,
+    capacity: usize,
+    notify: Notify,
+    forwarding: std::sync::Once,
+    /// The `Overflow` has been dropped, no item will be queued anymore
+    closed: AtomicBool,
+}
+
+impl<T: Send + 'static> OldestFirst<T> {
+    /// Queue the item, returns `true` if the oldest one has been dropped to make room.
+    fn push(self: &Arc<Self>, item: T, sender: &impl HandlerChannel<T>) -> bool {
+        let dropped = {
+            let mut messages = self.messages.lock().expect("OldestFirst mutex poisoned");
+            let dropped = messages.len() >= self.capacity.max(1);
+            if dropped {
+                messages.pop_front();
+            }
+            messages.push_back(item);
+            dropped
+        };
+        self.forwarding.call_once(|| {
+            tokio::spawn(Self::forward(self.clone(), sender.clone()));
+        });
+        self.notify.notify_one();
+        dropped
+    }
+
+    async fn forward(self: Arc<Self>, sender: impl HandlerChannel<T>) {
+        loop {
+            let item = self
+                .messages
+                .lock()
+                .expect("OldestFirst mutex poisoned")
+                .pop_front();
+            match item {
+                Some(item) => {
+                    if sender.send(item).await.is_err() {
+                        return;
+                    }
+                }
+                None if self.closed.load(Ordering::Acquire) => return,
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+impl HandlerReceiver {
+    async fn recv(&mut self, id: ConnectionId) -> Option<Outcome> {
+        match self {
+            Self::Plain(r) => r.recv().await.map(|m| Outcome::Replies(vec![(id, m)])),
+            Self::Routed(r) => r
+                .recv()
+                .await
+                .map(|(id, m)| Outcome::Replies(vec![(id, m)])),
+            Self::Verdict(r) => r.recv().await.map(Outcome::Verdict),
+        }
+    }
+}
+
+/// What a handler is registered for. A bare message type matches messages of any subprotocol,
+/// `(Protocol, MessageType)` only matches messages received on connections set up for that
+/// protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandlerKey {
+    pub protocol: Option<Protocol>,
     pub message_type: MessageType,
+}
+
+impl HandlerKey {
+    pub(crate) fn matches(&self, protocol: Option<Protocol>, message_type: MessageType) -> bool {
+        self.message_type == message_type && (self.protocol.is_none() || self.protocol == protocol)
+    }
+
+    /// Whether a message can match both keys.
+    pub(crate) fn overlaps(&self, other: &Self) -> bool {
+        self.message_type == other.message_type
+            && (self.protocol.is_none()
+                || other.protocol.is_none()
+                || self.protocol == other.protocol)
+    }
+}
+
+impl From<MessageType> for HandlerKey {
+    fn from(message_type: MessageType) -> Self {
+        Self {
+            protocol: None,
+            message_type,
+        }
+    }
+}
+
+impl From<(Protocol, MessageType)> for HandlerKey {
+    fn from((protocol, message_type): (Protocol, MessageType)) -> Self {
+        Self {
+            protocol: Some(protocol),
+            message_type,
+        }
+    }
+}
+
+pub struct MessageChannel {
+    pub key: HandlerKey,
     pub expect_from: Remote,
-    pub receiver: Option<Receiver<PoolMessages<'static>>>,
-    pub sender: Sender<PoolMessages<'static>>,
+    pub receiver: Option<HandlerReceiver>,
+    pub sender: HandlerSender,
+    pub overflow: Overflow,
 }
 
 impl MessageChannel {
-    pub async fn on_message(&mut self, frame: &mut Frame_) -> Option<Frame_> {
-        let (mt, message) = self.message_from_frame(frame);
-        if mt == self.message_type {
-            if self.sender.send(message).await.is_err() {
+    /// Send the message to the handler, the `Dispatcher` only gives the messages that match the
+    /// key of the handler.
+    pub async fn on_message(
+        &mut self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        mt: MessageType,
+        message: PoolMessages<'static>,
+    ) -> Result<Outcome, MessageChannelError> {
+        self.send(id, received_at, mt, message).await?;
+        match &mut self.receiver {
+            Some(receiver) => receiver.recv(id).await.ok_or_else(|| {
+                eprintln!("Impossible to receive message from message handler, for: {mt}");
+                MessageChannelError::HandlerDropped(mt)
+            }),
+            None => Ok(Outcome::Observed),
+        }
+    }
+
+    async fn send(
+        &self,
+        id: ConnectionId,
+        received_at: SystemTime,
+        mt: MessageType,
+        message: PoolMessages<'static>,
+    ) -> Result<(), MessageChannelError> {
+        match self
+            .overflow
+            .send(&self.sender, (id, received_at, message))
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(OverflowError::Full) => {
+                eprintln!("Handler channel full, for: {mt}");
+                Err(MessageChannelError::HandlerOverflow(mt))
+            }
+            Err(OverflowError::Closed) => {
                 eprintln!("Impossible to send message to message handler, for: {mt}");
-                std::process::exit(1);
-            };
-            if let Some(receiver) = &mut self.receiver {
-                if let Some(message) = receiver.recv().await {
-                    let frame: StdFrame = message
-                        .try_into()
-                        .expect("A message can always be converted in a frame");
-                    Some(frame.into())
-                } else {
-                    eprintln!("Impossible to receive message from message handler, for: {mt}");
-                    std::process::exit(1);
-                }
-            } else {
-                None
+                Err(MessageChannelError::HandlerDropped(mt))
             }
-        } else {
-            None
-        }
-    }
-    fn message_from_frame(&self, frame: &mut Frame_) -> (u8, PoolMessages<'static>) {
-        let expect_from = &self.expect_from;
-        match frame {
-            EitherFrame::Sv2(frame) => {
-                if let Some(header) = frame.get_header() {
-                    let mt = header.msg_type();
-                    let mut payload = frame.payload().to_vec();
-                    let mut payload2 = payload.clone();
-                    // TODO TODO TODO we need todo this orrible thing cause
-                    // that https://github.com/stratum-mining/stratum/issues/936
-                    // as soon as fixed remove it
-                    let maybe_message: Result<PoolMessages<'_>, _> =
-                        (mt, payload.as_mut_slice()).try_into();
-                    let maybe_message2: Result<TemplateDistribution<'_>, _> =
-                        (mt, payload2.as_mut_slice()).try_into();
-                    match (maybe_message, maybe_message2) {
-                        (Ok(message), _) => (mt, into_static(message)),
-                        (_, Ok(message)) => {
-                            (mt, into_static(PoolMessages::TemplateDistribution(message)))
-                        }
-                        _ => {
-                            eprintln!("Received frame with invalid payload or message type: {frame:?}, from: {expect_from}");
-                            std::process::exit(1);
-                        }
+        }
+    }
+}
+
+/// Sees every message that the application sends with the message sender, before it is sent to
+/// the remote, and answers with the message to send in its place.
+pub struct Interceptor {
+    pub sender: Sender<PoolMessages<'static>>,
+    pub receiver: Receiver<PoolMessages<'static>>,
+}
+
+impl Interceptor {
+    /// Run `message` through `interceptors` in the order they were added, `None` if one of them
+    /// has been dropped.
+    pub async fn intercept(
+        interceptors: &mut [Interceptor],
+        mut message: PoolMessages<'static>,
+    ) -> Option<PoolMessages<'static>> {
+        for interceptor in interceptors.iter_mut() {
+            interceptor.sender.send(message).await.ok()?;
+            message = interceptor.receiver.recv().await?;
+        }
+        Some(message)
+    }
+}
+
+/// The channel of a handler of events, eg the invalid frames or the negotiated extensions, with
+/// its overflow policy. An event that is not sent is only logged.
+pub(crate) struct Observer<T> {
+    sender: Sender<T>,
+    overflow: Overflow<T>,
+}
+
+impl<T: Send + 'static> Observer<T> {
+    pub(crate) fn new(config: HandlerConfig) -> (Self, Receiver<T>, DropCounter) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let overflow = Overflow::new(config, dropped.clone());
+        (Self { sender, overflow }, receiver, dropped)
+    }
+
+    /// Send `item` to the handler, `what` names the event in the logs.
+    pub(crate) async fn send(&self, item: T, what: &str) {
+        match self.overflow.send(&self.sender, item).await {
+            Ok(()) => (),
+            Err(OverflowError::Full) => eprintln!("Handler channel full, dropped: {what}"),
+            Err(OverflowError::Closed) => eprintln!("Impossible to send {what} to its handler"),
+        }
+    }
+}
+
+pub(crate) fn into_frame(message: PoolMessages<'static>) -> Frame_ {
+    let frame: StdFrame = message
+        .try_into()
+        .expect("A message can always be converted in a frame");
+    frame.into()
+}
+
+/// Extension id in the `extension_type` of a frame header, the other bit is `channel_msg`.
+pub(crate) const EXTENSION_TYPE_MASK: u16 = 0x7fff;
+
+/// The message type of a frame of the core protocols, `None` for the frames of an extension.
+pub(crate) fn message_type(frame: &Frame_) -> Result<Option<MessageType>, FrameError> {
+    match frame {
+        EitherFrame::Sv2(frame) => match frame.get_header() {
+            Some(header) if header.ext_type() & EXTENSION_TYPE_MASK == 0 => {
+                Ok(Some(header.msg_type()))
+            }
+            Some(_) => Ok(None),
+            None => Err(FrameError::InvalidHeader),
+        },
+        EitherFrame::HandShake(_) => Err(FrameError::UnexpectedHandshakeFrame),
+    }
+}
+
+/// Decode the frame as a message of `protocol`, when the protocol of the connection is not known
+/// yet (eg before the `SetupConnection`) the message type alone is used to find the subprotocol.
+pub(crate) fn message_from_frame(
+    frame: &mut Frame_,
+    expect_from: Remote,
+    protocol: Option<Protocol>,
+) -> Result<(u8, PoolMessages<'static>), FrameError> {
+    match frame {
+        EitherFrame::Sv2(frame) => {
+            if let Some(header) = frame.get_header() {
+                let mt = header.msg_type();
+                // Decoding does not modify the payload, so a proxy can still forward the frame
+                let payload = frame.payload();
+                let is_common = CommonMessageTypes::try_from(mt).is_ok();
+                let maybe_message: Result<PoolMessages<'_>, _> = match protocol {
+                    _ if is_common => {
+                        CommonMessages::try_from((mt, payload)).map(PoolMessages::Common)
+                    }
+                    Some(Protocol::MiningProtocol) => {
+                        Mining::try_from((mt, payload)).map(PoolMessages::Mining)
+                    }
+                    Some(Protocol::JobDeclarationProtocol) => {
+                        JobDeclaration::try_from((mt, payload)).map(PoolMessages::JobDeclaration)
+                    }
+                    Some(Protocol::TemplateDistributionProtocol) => {
+                        TemplateDistribution::try_from((mt, payload))
+                            .map(PoolMessages::TemplateDistribution)
+                    }
+                    None => (mt, payload).try_into(),
+                };
+                match maybe_message {
+                    Ok(message) => Ok((mt, into_static(message))),
+                    Err(_) => {
+                        eprintln!("Received frame with invalid payload or message type: {frame:?}, from: {expect_from}");
+                        Err(FrameError::InvalidPayload(mt))
                     }
-                } else {
-                    eprintln!("Received frame with invalid header: {frame:?}, from: {expect_from}");
-                    std::process::exit(1);
                 }
-            }
-            EitherFrame::HandShake(f) => {
-                eprintln!("Received unexpected handshake frame: {f:?}, from: {expect_from}");
-                std::process::exit(1);
+            } else {
+                eprintln!("Received frame with invalid header: {frame:?}, from: {expect_from}");
+                Err(FrameError::InvalidHeader)
             }
         }
+        EitherFrame::HandShake(f) => {
+            eprintln!("Received unexpected handshake frame: {f:?}, from: {expect_from}");
+            Err(FrameError::UnexpectedHandshakeFrame)
+        }
     }
 }
+
+/// Why a frame received from a remote can not be dispatched to the handlers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameError {
+    InvalidPayload(MessageType),
+    InvalidHeader,
+    UnexpectedHandshakeFrame,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageChannelError {
+    InvalidFrame(FrameError),
+    /// The application dropped the channels of the handler for this message type
+    HandlerDropped(MessageType),
+    /// The channel of a handler with `OverflowPolicy::Disconnect` is full
+    HandlerOverflow(MessageType),
+}
+
+impl From<FrameError> for MessageChannelError {
+    fn from(value: FrameError) -> Self {
+        Self::InvalidFrame(value)
+    }
+}
+
+/// What to do when a remote sends a frame that can not be decoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidFramePolicy {
+    /// Ignore the frame and keep reading from the remote
+    DropFrame,
+    /// Close the connection with the remote that sent the frame, on a listening `Server` the
+    /// other downstreams are not affected
+    #[default]
+    CloseConnection,
+    /// Stop the `Client`, `Server` or `Proxy` returning the error from `start`
+    Abort,
+}
+
+/// Sent to the invalid frame handler every time a frame can not be decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InvalidFrame {
+    pub connection: ConnectionId,
+    pub from: Remote,
+    pub error: FrameError,
+}
+
+pub(crate) struct InvalidFrames {
+    pub policy: InvalidFramePolicy,
+    pub observer: Option<Observer<InvalidFrame>>,
+}
+
+impl InvalidFrames {
+    /// Notify the invalid frame handler, if any, and return the policy to apply
+    pub async fn on_invalid_frame(
+        &self,
+        connection: ConnectionId,
+        from: Remote,
+        error: FrameError,
+    ) -> InvalidFramePolicy {
+        if let Some(observer) = &self.observer {
+            let invalid_frame = InvalidFrame {
+                connection,
+                from,
+                error,
+            };
+            observer.send(invalid_frame, "invalid frame").await;
+        }
+        self.policy
+    }
+}
+
 impl std::fmt::Display for Remote {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -92,3 +748,11 @@ impl std::fmt::Display for Remote {
         }
     }
 }
+
+/// Receive from `receiver` if there is one, otherwise never return.
+pub(crate) async fn recv_or_pending<T>(receiver: &mut Option<Receiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
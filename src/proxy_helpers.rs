@@ -1,23 +1,59 @@
 use codec_sv2::{HandshakeRole, Initiator, Responder};
 pub use const_sv2;
-use demand_sv2_connection::noise_connection_tokio::Connection;
 use key_utils::{Error as KeyUtilsError, Secp256k1PublicKey, Secp256k1SecretKey};
 pub use roles_logic_sv2;
 pub use roles_logic_sv2::parsers::PoolMessages;
+use roles_logic_sv2::{common_messages_sv2::Protocol, parsers::CommonMessages};
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
 use tokio::{
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
     select,
     sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
 };
 
-use crate::message_channel::MessageChannel;
+use crate::connection::{
+    is_loopback, noise_connection, peer_name, plain_connection, recv_within, Timeouts,
+};
+use crate::extension::{ExtensionMessage, RawChannel, RawFrame, RawHandler};
+use crate::handler::{
+    push_handler, AnyHandler, Dispatcher, Handler, MessageHandler, OwnerConflict, TypedMessage,
+};
+use crate::inbound::{Inbound, InboundSender, Origins};
+use crate::keys::{AuthorityKeyPair, KeysError};
+use crate::message_channel::{
+    into_frame, message_from_frame, message_type, recv_or_pending, DropCounter, FrameError,
+    HandlerConfig, HandlerKey, HandlerReceiver, HandlerSender, InvalidFrame, InvalidFramePolicy,
+    InvalidFrames, MessageChannel, MessageChannelError, MessageType, Observer, Outcome, Overflow,
+    Typed, Verdict,
+};
+use crate::server_helpers::{DEFAULT_PUB_KEY, DEFAULT_SEC_KEY};
+use crate::shutdown::ShutdownSignal;
 use crate::Frame_;
 use crate::Remote;
+use crate::ShutdownHandle;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ProxyError {
     DownstreamClosed,
     UpstreamClosed,
+    InvalidFrame(FrameError),
+    HandlerDropped(MessageType),
+    /// The channel of a handler with `OverflowPolicy::Disconnect` is full
+    HandlerOverflow(MessageType),
+    /// A frame from the extension sender has a payload of 2^24 bytes or more
+    PayloadTooLong(MessageType),
+    /// Nothing has been received from the downstream or from the upstream for longer than the
+    /// idle timeout
+    IdleTimeout,
+    /// The downstream did not send its first frame, or the upstream did not answer, within the
+    /// setup timeout
+    SetupTimeout,
 }
 
 pub struct Proxy {
@@ -25,67 +61,333 @@ pub struct Proxy {
     to_client: Sender<Frame_>,
     from_server: Receiver<Frame_>,
     to_server: Sender<Frame_>,
-    handlers: Vec<MessageChannel>,
+    client_handlers: Dispatcher,
+    server_handlers: Dispatcher,
+    extensions_to_send: Option<Receiver<(Remote, RawFrame)>>,
+    invalid_frames: InvalidFrames,
+    timeouts: Timeouts,
+    shutdown: ShutdownSignal,
+    client_origins: Origins,
+    server_origins: Origins,
 }
 
 impl Proxy {
-    pub async fn start(self) -> Result<(), ProxyError> {
-        let mut client_handlers = vec![];
-        let mut server_handlers = vec![];
-        for handler in self.handlers {
-            match handler.expect_from {
-                Remote::Client => client_handlers.push(handler),
-                Remote::Server => server_handlers.push(handler),
+    pub async fn start(mut self) -> Result<(), ProxyError> {
+        let peers = Peers {
+            to_client: &self.to_client,
+            to_server: &self.to_server,
+            client_origins: &self.client_origins,
+            server_origins: &self.server_origins,
+        };
+        // The protocol is learned from the SetupConnection that the downstream sends upstream
+        let protocol = OnceLock::new();
+        tokio::try_join!(
+            Self::recv_from_down_send_to_up(
+                &mut self.from_client,
+                &peers,
+                &mut self.client_handlers,
+                &protocol,
+                &self.invalid_frames,
+                self.timeouts,
+                &self.shutdown,
+            ),
+            Self::recv_from_up_send_to_down(
+                &mut self.from_server,
+                &peers,
+                &mut self.server_handlers,
+                &protocol,
+                &self.invalid_frames,
+                self.timeouts,
+                &self.shutdown,
+            ),
+            Self::send_extensions(&mut self.extensions_to_send, &peers, &self.shutdown),
+        )?;
+        for remote in [Remote::Server, Remote::Client] {
+            for message in self.shutdown.goodbye(remote) {
+                peers.send(remote, into_frame(message)).await?;
             }
         }
-        select! {
-            r = Self::recv_from_down_send_to_up(self.from_client, self.to_server, client_handlers) => r,
-            r = Self::recv_from_up_send_to_down(self.from_server, self.to_client, server_handlers) => r,
+        Ok(())
+    }
+
+    /// Send the frames from the extension sender to the downstream or to the upstream.
+    async fn send_extensions(
+        recv: &mut Option<Receiver<(Remote, RawFrame)>>,
+        peers: &Peers<'_>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<(), ProxyError> {
+        loop {
+            let (to, frame) = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Flush the frames already queued
+                    while let Some(Ok((to, frame))) = recv.as_mut().map(Receiver::try_recv) {
+                        Self::send_extension(to, frame, peers).await?;
+                    }
+                    return Ok(());
+                }
+                received = recv_or_pending(recv) => match received {
+                    Some(received) => received,
+                    None => {
+                        // The extension sender has been dropped
+                        *recv = None;
+                        continue;
+                    }
+                },
+            };
+            Self::send_extension(to, frame, peers).await?;
         }
     }
 
+    async fn send_extension(
+        to: Remote,
+        frame: RawFrame,
+        peers: &Peers<'_>,
+    ) -> Result<(), ProxyError> {
+        let message_type = frame.message_type;
+        let frame = frame
+            .into_frame()
+            .ok_or(ProxyError::PayloadTooLong(message_type))?;
+        peers.send(to, frame.into()).await
+    }
+
     async fn recv_from_down_send_to_up(
-        mut recv: Receiver<Frame_>,
-        send: Sender<Frame_>,
-        mut handlers: Vec<MessageChannel>,
+        recv: &mut Receiver<Frame_>,
+        peers: &Peers<'_>,
+        handlers: &mut Dispatcher,
+        protocol: &OnceLock<Protocol>,
+        invalid_frames: &InvalidFrames,
+        timeouts: Timeouts,
+        shutdown: &ShutdownSignal,
     ) -> Result<(), ProxyError> {
-        while let Some(mut frame) = recv.recv().await {
-            let mut send_original_frame_upstream = true;
-            for handler in handlers.iter_mut() {
-                if let Some(frame) = handler.on_message(&mut frame).await {
-                    send_original_frame_upstream = false;
-                    if send.send(frame).await.is_err() {
-                        return Err(ProxyError::UpstreamClosed);
-                    };
+        // The first frame is the SetupConnection
+        let mut setup = true;
+        loop {
+            let within = if setup {
+                Some(timeouts.setup)
+            } else {
+                timeouts.idle
+            };
+            let frame = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Forward the frames already received
+                    while let Ok(frame) = recv.try_recv() {
+                        Self::on_frame_from_down(frame, SystemTime::now(), peers, handlers, protocol, invalid_frames).await?;
+                    }
+                    return Ok(());
                 }
-            }
-            if send_original_frame_upstream && send.send(frame).await.is_err() {
-                return Err(ProxyError::UpstreamClosed);
+                frame = recv_within(recv, within) => match frame {
+                    Ok(frame) => frame.ok_or(ProxyError::DownstreamClosed)?,
+                    Err(_) if setup => {
+                        eprintln!("SetupConnection not received from downstream for {within:?}");
+                        return Err(ProxyError::SetupTimeout);
+                    }
+                    Err(_) => {
+                        eprintln!("Nothing received from downstream for {within:?}");
+                        return Err(ProxyError::IdleTimeout);
+                    }
+                },
             };
+            setup = false;
+            Self::on_frame_from_down(
+                frame,
+                SystemTime::now(),
+                peers,
+                handlers,
+                protocol,
+                invalid_frames,
+            )
+            .await?;
         }
-        Err(ProxyError::DownstreamClosed)
+    }
+
+    async fn on_frame_from_down(
+        mut frame: Frame_,
+        received_at: SystemTime,
+        peers: &Peers<'_>,
+        handlers: &mut Dispatcher,
+        protocol: &OnceLock<Protocol>,
+        invalid_frames: &InvalidFrames,
+    ) -> Result<(), ProxyError> {
+        if protocol.get().is_none()
+            && message_type(&frame) == Ok(Some(const_sv2::MESSAGE_TYPE_SETUP_CONNECTION))
+        {
+            if let Ok((_, PoolMessages::Common(CommonMessages::SetupConnection(m)))) =
+                message_from_frame(&mut frame, Remote::Client, None)
+            {
+                let _ = protocol.set(m.protocol);
+                peers.client_origins.set_setup(0, m.clone());
+                peers.server_origins.set_setup(0, m);
+            }
+        }
+        Self::on_frame(
+            frame,
+            received_at,
+            Remote::Client,
+            peers,
+            handlers,
+            protocol.get().copied(),
+            invalid_frames,
+        )
+        .await
     }
 
     async fn recv_from_up_send_to_down(
-        mut recv: Receiver<Frame_>,
-        send: Sender<Frame_>,
-        mut handlers: Vec<MessageChannel>,
+        recv: &mut Receiver<Frame_>,
+        peers: &Peers<'_>,
+        handlers: &mut Dispatcher,
+        protocol: &OnceLock<Protocol>,
+        invalid_frames: &InvalidFrames,
+        timeouts: Timeouts,
+        shutdown: &ShutdownSignal,
     ) -> Result<(), ProxyError> {
-        while let Some(mut frame) = recv.recv().await {
-            let mut send_original_frame_upstream = true;
-            for handler in handlers.iter_mut() {
-                if let Some(frame) = handler.on_message(&mut frame).await {
-                    send_original_frame_upstream = false;
-                    if send.send(frame).await.is_err() {
-                        return Err(ProxyError::DownstreamClosed);
-                    };
+        // The first frame is the answer to the SetupConnection, the deadline is the same as for
+        // the downstream to send it
+        let mut setup = true;
+        loop {
+            let within = if setup {
+                Some(timeouts.setup)
+            } else {
+                timeouts.idle
+            };
+            let frame = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Forward the frames already received
+                    while let Ok(frame) = recv.try_recv() {
+                        Self::on_frame_from_up(frame, SystemTime::now(), peers, handlers, protocol.get().copied(), invalid_frames).await?;
+                    }
+                    return Ok(());
                 }
-            }
-            if send_original_frame_upstream && send.send(frame).await.is_err() {
-                return Err(ProxyError::DownstreamClosed);
+                frame = recv_within(recv, within) => match frame {
+                    Ok(frame) => frame.ok_or(ProxyError::UpstreamClosed)?,
+                    Err(_) if setup => {
+                        eprintln!("SetupConnection not answered by upstream for {within:?}");
+                        return Err(ProxyError::SetupTimeout);
+                    }
+                    Err(_) => {
+                        eprintln!("Nothing received from upstream for {within:?}");
+                        return Err(ProxyError::IdleTimeout);
+                    }
+                },
             };
+            setup = false;
+            Self::on_frame_from_up(
+                frame,
+                SystemTime::now(),
+                peers,
+                handlers,
+                protocol.get().copied(),
+                invalid_frames,
+            )
+            .await?;
         }
-        Err(ProxyError::UpstreamClosed)
+    }
+
+    async fn on_frame_from_up(
+        mut frame: Frame_,
+        received_at: SystemTime,
+        peers: &Peers<'_>,
+        handlers: &mut Dispatcher,
+        protocol: Option<Protocol>,
+        invalid_frames: &InvalidFrames,
+    ) -> Result<(), ProxyError> {
+        if message_type(&frame) == Ok(Some(const_sv2::MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS)) {
+            if let Ok((_, PoolMessages::Common(CommonMessages::SetupConnectionSuccess(m)))) =
+                message_from_frame(&mut frame, Remote::Server, protocol)
+            {
+                peers.client_origins.set_success(0, m);
+                peers.server_origins.set_success(0, m);
+            }
+        }
+        Self::on_frame(
+            frame,
+            received_at,
+            Remote::Server,
+            peers,
+            handlers,
+            protocol,
+            invalid_frames,
+        )
+        .await
+    }
+
+    /// Run the handlers on a frame received from `from`, then forward it to the other remote
+    /// unless a handler replied or gave a verdict that does not forward it.
+    async fn on_frame(
+        mut frame: Frame_,
+        received_at: SystemTime,
+        from: Remote,
+        peers: &Peers<'_>,
+        handlers: &mut Dispatcher,
+        protocol: Option<Protocol>,
+        invalid_frames: &InvalidFrames,
+    ) -> Result<(), ProxyError> {
+        let to = match from {
+            Remote::Client => Remote::Server,
+            Remote::Server => Remote::Client,
+        };
+        let mut forward = true;
+        let mut dispatch = handlers.dispatch(0, received_at, protocol, &mut frame);
+        while let Some(outcome) = dispatch.next().await {
+            match outcome {
+                Ok(Outcome::Observed) | Ok(Outcome::Verdict(Verdict::Forward)) => (),
+                Ok(Outcome::Replies(replies)) => {
+                    forward = false;
+                    for (_, message) in replies {
+                        peers.send(to, into_frame(message)).await?;
+                    }
+                }
+                Ok(Outcome::Frames(frames)) => {
+                    forward = false;
+                    for (_, frame) in frames {
+                        peers.send(to, frame).await?;
+                    }
+                }
+                Ok(Outcome::Verdict(Verdict::Drop)) => forward = false,
+                Ok(Outcome::Verdict(Verdict::Replace(message))) => {
+                    forward = false;
+                    peers.send(to, into_frame(*message)).await?;
+                }
+                Ok(Outcome::Verdict(Verdict::Emit(messages))) => {
+                    forward = false;
+                    for (remote, message) in messages {
+                        peers.send(remote, into_frame(message)).await?;
+                    }
+                }
+                Err(MessageChannelError::InvalidFrame(e)) => {
+                    return match invalid_frames.on_invalid_frame(0, from, e).await {
+                        InvalidFramePolicy::DropFrame => Ok(()),
+                        _ => Err(ProxyError::InvalidFrame(e)),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if forward {
+            peers.send(to, frame).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The senders to the downstream and to the upstream, and what the inbound handlers of each
+/// side know about the connection.
+struct Peers<'a> {
+    to_client: &'a Sender<Frame_>,
+    to_server: &'a Sender<Frame_>,
+    client_origins: &'a Origins,
+    server_origins: &'a Origins,
+}
+
+impl Peers<'_> {
+    async fn send(&self, to: Remote, frame: Frame_) -> Result<(), ProxyError> {
+        let (send, error) = match to {
+            Remote::Client => (self.to_client, ProxyError::DownstreamClosed),
+            Remote::Server => (self.to_server, ProxyError::UpstreamClosed),
+        };
+        send.send(frame).await.map_err(|_| error)
     }
 }
 
@@ -95,10 +397,18 @@ pub struct ProxyBuilder {
     from_server: Option<Receiver<Frame_>>,
     to_server: Option<Sender<Frame_>>,
     cert_validity: u64,
+    timeouts: Timeouts,
     proxy_pub_key: Secp256k1PublicKey,
     proxy_sec_key: Secp256k1SecretKey,
+    refuse_default_keys: bool,
     server_auth_key: Option<Secp256k1PublicKey>,
-    handlers: Vec<MessageChannel>,
+    handlers: Vec<AnyHandler>,
+    extensions_to_send: Option<Receiver<(Remote, RawFrame)>>,
+    invalid_frame_policy: InvalidFramePolicy,
+    invalid_frame_handler: Option<Observer<InvalidFrame>>,
+    shutdown: Option<ShutdownHandle>,
+    client_origins: Origins,
+    server_origins: Origins,
 }
 
 #[derive(Debug)]
@@ -109,6 +419,17 @@ pub enum ProxyBuilderError {
     IncompleteBuilder,
     CanNotHaveMoreThan1Client,
     CanNotHaveMoreThan1Server,
+    DefaultKeysNotAllowed,
+    KeyPairMismatch,
+    KeyFileError(KeysError),
+    MissingKeyEnvVar(String),
+    /// A handler with a reply is already registered for a message of this key
+    OwnerAlreadyRegistered(HandlerKey),
+    MessageHandlerAlreadyRegistered,
+    /// Unencrypted connections are only accepted on the loopback interface
+    UnencryptedNotOnLoopback,
+    /// The client or the server did not complete the Noise handshake in time
+    HandshakeTimeout,
 }
 
 impl ProxyBuilder {
@@ -119,16 +440,18 @@ impl ProxyBuilder {
             from_server: None,
             to_server: None,
             cert_validity: 10000,
-            proxy_pub_key: "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
-                .to_string()
-                .parse()
-                .expect("Invalid default pub key"),
-            proxy_sec_key: "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
-                .to_string()
-                .parse()
-                .expect("Invalid default sec key"),
+            timeouts: Timeouts::default(),
+            proxy_pub_key: DEFAULT_PUB_KEY.parse().expect("Invalid default pub key"),
+            proxy_sec_key: DEFAULT_SEC_KEY.parse().expect("Invalid default sec key"),
+            refuse_default_keys: false,
             server_auth_key: None,
             handlers: vec![],
+            extensions_to_send: None,
+            invalid_frame_policy: InvalidFramePolicy::default(),
+            invalid_frame_handler: None,
+            shutdown: None,
+            client_origins: Origins::default(),
+            server_origins: Origins::default(),
         }
     }
 
@@ -150,23 +473,75 @@ impl ProxyBuilder {
         &mut self,
         stream: TcpStream,
     ) -> Result<&mut Self, ProxyBuilderError> {
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_client_stream(stream, &peer).await?;
+        self.client_origins.set_address(0, address);
+        Ok(self)
+    }
+
+    /// Like `try_add_client` but the frames are exchanged without the Noise handshake and
+    /// without encryption. The stream must be on the loopback interface, otherwise this fails
+    /// with `UnencryptedNotOnLoopback`.
+    pub fn try_add_client_unencrypted(
+        &mut self,
+        stream: TcpStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        if !is_loopback(stream.local_addr()) {
+            return Err(ProxyBuilderError::UnencryptedNotOnLoopback);
+        }
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_client_stream_unencrypted(stream, &peer)?;
+        self.client_origins.set_address(0, address);
+        Ok(self)
+    }
+
+    /// Like `try_add_client` but over a unix domain socket.
+    pub async fn try_add_client_unix(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        self.add_client_stream(stream, "unix socket").await
+    }
+
+    /// Like `try_add_client_unencrypted` but over a unix domain socket, that is always local.
+    pub fn try_add_client_unix_unencrypted(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        self.add_client_stream_unencrypted(stream, "unix socket")
+    }
+
+    /// Like `try_add_client` but over any stream, for example a custom tunnel. The Noise
+    /// handshake and the SV2 framing are done on top of the stream. There is no unencrypted
+    /// variant since it is not possible to tell if a stream is local.
+    pub async fn try_add_client_stream<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_client_stream(stream, "stream").await
+    }
+
+    async fn add_client_stream<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
         if self.from_client.is_none() && self.to_client.is_none() {
-            let auth_pub_k_as_bytes = self.proxy_pub_key.into_bytes();
-            let auth_prv_k_as_bytes = self.proxy_sec_key.into_bytes();
-            let responder = Responder::from_authority_kp(
-                &auth_pub_k_as_bytes,
-                &auth_prv_k_as_bytes,
-                std::time::Duration::from_secs(self.cert_validity),
+            let connection = timeout(
+                self.timeouts.handshake,
+                noise_connection(stream, peer, self.responder()?),
             )
-            .expect("invalid key pair");
-
-            if let Ok((receiver_from_client, send_to_client, _, _)) =
-                Connection::new::<'static, PoolMessages<'static>>(
-                    stream,
-                    HandshakeRole::Responder(responder),
-                )
-                .await
-            {
+            .await
+            .map_err(|_| ProxyBuilderError::HandshakeTimeout)?;
+            if let Some((receiver_from_client, send_to_client, _)) = connection {
                 self.from_client = Some(receiver_from_client);
                 self.to_client = Some(send_to_client);
                 Ok(self)
@@ -178,6 +553,24 @@ impl ProxyBuilder {
         }
     }
 
+    fn add_client_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if self.from_client.is_none() && self.to_client.is_none() {
+            let (receiver_from_client, send_to_client, _) = plain_connection(stream, peer);
+            self.from_client = Some(receiver_from_client);
+            self.to_client = Some(send_to_client);
+            Ok(self)
+        } else {
+            Err(ProxyBuilderError::CanNotHaveMoreThan1Client)
+        }
+    }
+
     pub fn try_with_server(
         &mut self,
         from_server: Receiver<Frame_>,
@@ -196,22 +589,80 @@ impl ProxyBuilder {
         &mut self,
         stream: TcpStream,
     ) -> Result<&mut Self, ProxyBuilderError> {
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_server_stream(stream, &peer).await?;
+        self.server_origins.set_address(0, address);
+        Ok(self)
+    }
+    /// Like `try_add_server` but the frames are exchanged without the Noise handshake and
+    /// without encryption. Only meant to talk with a local server (a Template Provider, a
+    /// sidecar) or to debug with a packet capture.
+    pub fn try_add_server_unencrypted(
+        &mut self,
+        stream: TcpStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_server_stream_unencrypted(stream, &peer)?;
+        self.server_origins.set_address(0, address);
+        Ok(self)
+    }
+    /// Like `try_add_server` but over a unix domain socket.
+    pub async fn try_add_server_unix(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        self.add_server_stream(stream, "unix socket").await
+    }
+    /// Like `try_add_server_unencrypted` but over a unix domain socket.
+    pub fn try_add_server_unix_unencrypted(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        self.add_server_stream_unencrypted(stream, "unix socket")
+    }
+    /// Like `try_add_server` but over any stream, for example a socket that goes through a
+    /// SOCKS proxy or a custom tunnel. The Noise handshake and the SV2 framing are done on top
+    /// of the stream.
+    pub async fn try_add_server_stream<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_server_stream(stream, "stream").await
+    }
+    /// Like `try_add_server_stream` but without the Noise handshake and without encryption, see
+    /// `try_add_server_unencrypted`.
+    pub fn try_add_server_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_server_stream_unencrypted(stream, "stream")
+    }
+    async fn add_server_stream<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
         if self.from_server.is_none() && self.to_server.is_none() {
-            let initiator = match self.server_auth_key {
-                Some(key) => Initiator::from_raw_k(key.into_bytes())
-                    .expect("Pub key is already checked for validity"),
-                None => Initiator::without_pk().expect("This fn call can not fail"),
-            };
-
-            if let Ok((receiver_from_client, send_to_client, _, _)) =
-                Connection::new::<'static, PoolMessages<'static>>(
-                    stream,
-                    HandshakeRole::Initiator(initiator),
-                )
-                .await
-            {
-                self.from_server = Some(receiver_from_client);
-                self.to_server = Some(send_to_client);
+            let connection = timeout(
+                self.timeouts.handshake,
+                noise_connection(stream, peer, self.initiator()),
+            )
+            .await
+            .map_err(|_| ProxyBuilderError::HandshakeTimeout)?;
+            if let Some((receiver_from_server, send_to_server, _)) = connection {
+                self.from_server = Some(receiver_from_server);
+                self.to_server = Some(send_to_server);
                 Ok(self)
             } else {
                 Err(ProxyBuilderError::ImpossibleToCompleteHandShakeWithUpstream)
@@ -220,6 +671,72 @@ impl ProxyBuilder {
             Err(ProxyBuilderError::CanNotHaveMoreThan1Server)
         }
     }
+    fn add_server_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ProxyBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if self.from_server.is_none() && self.to_server.is_none() {
+            let (receiver_from_server, send_to_server, _) = plain_connection(stream, peer);
+            self.from_server = Some(receiver_from_server);
+            self.to_server = Some(send_to_server);
+            Ok(self)
+        } else {
+            Err(ProxyBuilderError::CanNotHaveMoreThan1Server)
+        }
+    }
+    fn responder(&self) -> Result<HandshakeRole, ProxyBuilderError> {
+        let default_sec_key: Secp256k1SecretKey =
+            DEFAULT_SEC_KEY.parse().expect("Invalid default sec key");
+        if self.proxy_sec_key.into_bytes() == default_sec_key.into_bytes() {
+            if self.refuse_default_keys {
+                eprintln!("Refusing to use the default authority keys, override them");
+                return Err(ProxyBuilderError::DefaultKeysNotAllowed);
+            }
+            eprintln!("Using the default authority keys, anyone can impersonate the proxy");
+        }
+        if Secp256k1PublicKey::from(self.proxy_sec_key).into_bytes()
+            != self.proxy_pub_key.into_bytes()
+        {
+            return Err(ProxyBuilderError::KeyPairMismatch);
+        }
+        let responder = Responder::from_authority_kp(
+            &self.proxy_pub_key.into_bytes(),
+            &self.proxy_sec_key.into_bytes(),
+            std::time::Duration::from_secs(self.cert_validity),
+        )
+        .expect("invalid key pair");
+        Ok(HandshakeRole::Responder(responder))
+    }
+    fn initiator(&self) -> HandshakeRole {
+        let initiator = match self.server_auth_key {
+            Some(key) => Initiator::from_raw_k(key.into_bytes())
+                .expect("Pub key is already checked for validity"),
+            None => Initiator::without_pk().expect("This fn call can not fail"),
+        };
+        HandshakeRole::Initiator(initiator)
+    }
+    /// Maximum time for the client and the server to complete the Noise handshake. Default is
+    /// 10 seconds.
+    pub fn with_handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+    /// The proxy fails with `SetupTimeout` when the client does not send the SetupConnection, or
+    /// the server does not answer it, within `timeout` after `start`. Default is 10 seconds.
+    pub fn with_setup_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.setup = timeout;
+        self
+    }
+    /// The proxy fails with `IdleTimeout` when nothing is received from the client or from the
+    /// server for longer than `timeout`. By default there is no idle timeout.
+    pub fn with_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
     pub fn override_cert_validity(&mut self, cert_validity: u64) -> &mut Self {
         self.cert_validity = cert_validity;
         self
@@ -238,6 +755,38 @@ impl ProxyBuilder {
         self.proxy_sec_key = sec_key.parse()?;
         Ok(self)
     }
+    /// Read the proxy authority keys from a key file, see the `keys` module for the format.
+    pub fn with_keys_from_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        let key_pair = AuthorityKeyPair::read(path)?;
+        self.proxy_pub_key = key_pair.public_key;
+        self.proxy_sec_key = key_pair.secret_key;
+        Ok(self)
+    }
+    /// Read the base58 encoded proxy authority keys from the environment variables
+    /// `pub_key_var` and `sec_key_var`.
+    pub fn with_keys_from_env(
+        &mut self,
+        pub_key_var: &str,
+        sec_key_var: &str,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| ProxyBuilderError::MissingKeyEnvVar(name.to_string()))
+        };
+        self.proxy_pub_key = var(pub_key_var)?.parse()?;
+        self.proxy_sec_key = var(sec_key_var)?.parse()?;
+        Ok(self)
+    }
+    /// The default keys are the public keys of the stratum examples, anyone can use them to
+    /// impersonate the proxy. After this call `try_add_client` fails with
+    /// `DefaultKeysNotAllowed` if the keys have not been overridden, production proxies should
+    /// call it.
+    pub fn refuse_default_keys(&mut self) -> &mut Self {
+        self.refuse_default_keys = true;
+        self
+    }
     pub fn with_server_auth_key(
         &mut self,
         auth_key: String,
@@ -246,39 +795,305 @@ impl ProxyBuilder {
         self.server_auth_key = Some(auth_pub_k);
         Ok(self)
     }
+    /// Receive the messages of type `message_type` sent by `expect_from`: `Remote::Client` for
+    /// the messages that the downstream sends upstream, `Remote::Server` for the messages that the
+    /// upstream sends downstream. Messages are still forwarded, and every handler registered for
+    /// a message type receives its messages.
     pub fn add_handler(
         &mut self,
         expect_from: Remote,
-        message_type: u8,
+        message_type: impl Into<HandlerKey>,
     ) -> Receiver<PoolMessages<'static>> {
-        let (s, r) = channel(3);
+        self.add_handler_with_config(expect_from, message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_handler` but with the capacity of the channel and what to do when it is full,
+    /// the returned counter tells how many messages have been dropped. With
+    /// `OverflowPolicy::Disconnect` a full channel closes the proxy.
+    pub fn add_handler_with_config(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<PoolMessages<'static>>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
         let channel = MessageChannel {
-            message_type,
+            key: message_type.into(),
             expect_from,
             receiver: None,
-            sender: s,
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
         };
-        self.handlers.push(channel);
-        r
+        self.handlers.push(channel.into());
+        (r, dropped)
     }
+    /// Like `add_handler` but the message is not forwarded, the reply sent back with the returned
+    /// sender is forwarded in its place. A message type has at most one handler with a reply for
+    /// each direction, it receives the messages after the handlers without reply.
     pub fn add_handler_with_sender(
         &mut self,
         expect_from: Remote,
-        message_type: u8,
-    ) -> (
-        Receiver<PoolMessages<'static>>,
-        Sender<PoolMessages<'static>>,
-    ) {
-        let (s, r) = channel(3);
-        let (s1, r1) = channel(3);
+        message_type: impl Into<HandlerKey>,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+        ),
+        ProxyBuilderError,
+    > {
+        let (r, s, _) = self.add_handler_with_sender_with_config(
+            expect_from,
+            message_type,
+            HandlerConfig::default(),
+        )?;
+        Ok((r, s))
+    }
+    /// Like `add_handler_with_sender` but with the capacity of the channels and what to do when
+    /// they are full, like `add_handler_with_config`.
+    pub fn add_handler_with_sender_with_config(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+            DropCounter,
+        ),
+        ProxyBuilderError,
+    > {
+        let (s, r) = channel(config.channel_capacity());
+        let (s1, r1) = channel(config.capacity.max(1));
+        let dropped = DropCounter::default();
         let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from,
+            receiver: Some(HandlerReceiver::Plain(r1)),
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        push_handler(&mut self.handlers, channel)?;
+        Ok((r, s1, dropped))
+    }
+    /// Like `add_handler_with_sender` but for each message a `Verdict` must be sent back with the
+    /// returned sender: forward it, drop it, replace it, or send any number of messages to the
+    /// downstream and to the upstream in its place.
+    pub fn add_handler_with_verdict(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+    ) -> Result<(Receiver<PoolMessages<'static>>, Sender<Verdict>), ProxyBuilderError> {
+        let (r, s, _) = self.add_handler_with_verdict_with_config(
+            expect_from,
             message_type,
+            HandlerConfig::default(),
+        )?;
+        Ok((r, s))
+    }
+    /// Like `add_handler_with_verdict` but with the capacity of the channels and what to do when
+    /// they are full, like `add_handler_with_config`.
+    pub fn add_handler_with_verdict_with_config(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<Verdict>,
+            DropCounter,
+        ),
+        ProxyBuilderError,
+    > {
+        let (s, r) = channel(config.channel_capacity());
+        let (s1, r1) = channel(config.capacity.max(1));
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
             expect_from,
-            receiver: Some(r1),
-            sender: s,
+            receiver: Some(HandlerReceiver::Verdict(r1)),
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
         };
-        self.handlers.push(channel);
-        (r, s1)
+        push_handler(&mut self.handlers, channel)?;
+        Ok((r, s1, dropped))
+    }
+    /// Like `add_handler` but the message type comes from `T` and the messages are received as
+    /// `T`, eg `add_typed_handler::<SetTarget<'static>>(Remote::Server)`.
+    pub fn add_typed_handler<T: TypedMessage>(&mut self, expect_from: Remote) -> Receiver<T> {
+        self.add_typed_handler_with_config(expect_from, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_typed_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_typed_handler_with_config<T: TypedMessage>(
+        &mut self,
+        expect_from: Remote,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: T::MESSAGE_TYPE.into(),
+            expect_from,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(Typed(s))),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Like `add_handler` but every message comes in an `Inbound`, with the address of
+    /// `expect_from` and the SetupConnection sent by the downstream.
+    pub fn add_inbound_handler(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<Inbound> {
+        self.add_inbound_handler_with_config(expect_from, message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_inbound_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_inbound_handler_with_config(
+        &mut self,
+        expect_from: Remote,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<Inbound>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let origins = match expect_from {
+            Remote::Client => self.client_origins.clone(),
+            Remote::Server => self.server_origins.clone(),
+        };
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(InboundSender { sender: s, origins })),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Handle the messages sent by `expect_from` with `handler`, a `Reply::Send` is forwarded in
+    /// place of the received message. Only one `Handler` can be registered for each direction, it
+    /// does not receive the message types that have a handler with a reply.
+    pub fn add_message_handler(
+        &mut self,
+        expect_from: Remote,
+        handler: impl Handler,
+    ) -> Result<&mut Self, ProxyBuilderError> {
+        push_handler(
+            &mut self.handlers,
+            MessageHandler::new(expect_from, handler),
+        )?;
+        Ok(self)
+    }
+    /// Receive a copy of the frames of the extension `extension_type` sent by `expect_from`, the
+    /// frames are forwarded unchanged. The proxy only decodes the frames of the message types
+    /// that have a handler, every other frame is forwarded as it is received.
+    pub fn add_raw_handler(
+        &mut self,
+        expect_from: Remote,
+        extension_type: u16,
+    ) -> Receiver<RawFrame> {
+        self.add_raw_handler_with_config(expect_from, extension_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_raw_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_raw_handler_with_config(
+        &mut self,
+        expect_from: Remote,
+        extension_type: u16,
+        config: HandlerConfig,
+    ) -> (Receiver<RawFrame>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let sender = RawChannel::frames(s, Overflow::new(config, dropped.clone()));
+        self.handlers.push(
+            RawHandler {
+                expect_from,
+                extension_type,
+                message_type: None,
+                sender: Arc::new(sender),
+            }
+            .into(),
+        );
+        (r, dropped)
+    }
+    /// Like `add_raw_handler` but only for the messages of type `T`, received as `T`. The
+    /// extensions negotiation is forwarded like any other frame, so the downstream and the
+    /// upstream negotiate the extensions through the proxy.
+    pub fn add_extension_handler<T: ExtensionMessage>(
+        &mut self,
+        expect_from: Remote,
+    ) -> Receiver<T> {
+        self.add_extension_handler_with_config(expect_from, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_extension_handler` but with the capacity of the channel and what to do when it
+    /// is full, like `add_handler_with_config`.
+    pub fn add_extension_handler_with_config<T: ExtensionMessage>(
+        &mut self,
+        expect_from: Remote,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let sender = RawChannel::typed(s, Overflow::new(config, dropped.clone()));
+        self.handlers
+            .push(RawHandler::typed::<T>(expect_from, sender).into());
+        (r, dropped)
+    }
+    /// Frames sent here go to the downstream or to the upstream according to their `Remote`, eg
+    /// `(Remote::Server, RawFrame::from_message(&message))` for an extension message to send
+    /// upstream. A payload of 2^24 bytes or more makes `start` fail with
+    /// `ProxyError::PayloadTooLong`.
+    pub fn add_extension_sender(&mut self) -> Sender<(Remote, RawFrame)> {
+        self.add_extension_sender_with_capacity(3)
+    }
+    /// Like `add_extension_sender` but with a channel that holds `capacity` frames.
+    pub fn add_extension_sender_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Sender<(Remote, RawFrame)> {
+        let (s, r) = channel(capacity.max(1));
+        self.extensions_to_send = Some(r);
+        s
+    }
+    /// What to do when the downstream or the upstream send a frame that can not be decoded, the
+    /// default is to close the proxy.
+    pub fn with_invalid_frame_policy(&mut self, policy: InvalidFramePolicy) -> &mut Self {
+        self.invalid_frame_policy = policy;
+        self
+    }
+    /// Receive an `InvalidFrame` every time the downstream or the upstream send a frame that can
+    /// not be decoded.
+    pub fn add_invalid_frame_handler(&mut self) -> Receiver<InvalidFrame> {
+        self.add_invalid_frame_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_invalid_frame_handler` but with the capacity of the channel and what to do when
+    /// it is full, like `add_handler_with_config`.
+    pub fn add_invalid_frame_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<InvalidFrame>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.invalid_frame_handler = Some(observer);
+        (r, dropped)
+    }
+    /// A `ShutdownHandle` to stop the proxy, the goodbye messages are sent to the downstream or
+    /// to the upstream according to their `Remote`.
+    pub fn add_shutdown_handle(&mut self) -> ShutdownHandle {
+        self.shutdown
+            .get_or_insert_with(ShutdownHandle::new)
+            .clone()
     }
     pub fn try_build(self) -> Result<Proxy, ProxyBuilderError> {
         if let (Some(from_client), Some(to_client), Some(from_server), Some(to_server)) = (
@@ -287,20 +1102,65 @@ impl ProxyBuilder {
             self.from_server,
             self.to_server,
         ) {
+            let (client_handlers, server_handlers) = self
+                .handlers
+                .into_iter()
+                .partition(|handler| handler.expect_from() == Remote::Client);
             Ok(Proxy {
                 from_client,
                 to_client,
                 from_server,
                 to_server,
-                handlers: self.handlers,
+                client_handlers: Dispatcher::new(Remote::Client, client_handlers),
+                server_handlers: Dispatcher::new(Remote::Server, server_handlers),
+                extensions_to_send: self.extensions_to_send,
+                invalid_frames: InvalidFrames {
+                    policy: self.invalid_frame_policy,
+                    observer: self.invalid_frame_handler,
+                },
+                timeouts: self.timeouts,
+                shutdown: ShutdownSignal::from_handle(self.shutdown),
+                client_origins: self.client_origins,
+                server_origins: self.server_origins,
             })
         } else {
             Err(ProxyBuilderError::IncompleteBuilder)
         }
     }
 }
+impl From<MessageChannelError> for ProxyError {
+    fn from(value: MessageChannelError) -> Self {
+        match value {
+            MessageChannelError::InvalidFrame(e) => Self::InvalidFrame(e),
+            MessageChannelError::HandlerDropped(mt) => Self::HandlerDropped(mt),
+            MessageChannelError::HandlerOverflow(mt) => Self::HandlerOverflow(mt),
+        }
+    }
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<KeysError> for ProxyBuilderError {
+    fn from(value: KeysError) -> Self {
+        Self::KeyFileError(value)
+    }
+}
+
 impl From<KeyUtilsError> for ProxyBuilderError {
     fn from(value: KeyUtilsError) -> Self {
         Self::KeyError(value)
     }
 }
+
+impl From<OwnerConflict> for ProxyBuilderError {
+    fn from(value: OwnerConflict) -> Self {
+        match value {
+            OwnerConflict::Key(key) => Self::OwnerAlreadyRegistered(key),
+            OwnerConflict::MessageHandler => Self::MessageHandlerAlreadyRegistered,
+        }
+    }
+}
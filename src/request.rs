@@ -0,0 +1,459 @@
+use const_sv2::{
+    MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS, MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
+    MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS, MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS,
+    MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES, MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR,
+    MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+    MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS, MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR,
+    MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS,
+};
+use roles_logic_sv2::parsers::{JobDeclaration, Mining, PoolMessages};
+use std::{collections::HashMap, sync::Mutex as StdMutex, time::Duration};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::message_channel::{ConnectionId, MessageType};
+
+/// A message with a `request_id` waiting to be sent by a `Client` or a `Server`.
+pub(crate) struct Request {
+    connection: ConnectionId,
+    message: PoolMessages<'static>,
+    respond_to: oneshot::Sender<PoolMessages<'static>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestError {
+    /// No response has been received before the timeout.
+    Timeout,
+    /// The message is not a request with a `request_id`.
+    NotARequest,
+    /// The connection has been closed before receiving a response.
+    ConnectionClosed,
+}
+
+/// Send requests to a peer and wait for their response. The `request_id` of the message is
+/// replaced with one assigned by the `Client` or the `Server`, and the `Success` or `Error` with
+/// the same `request_id` is returned to the caller instead of being sent to the handlers.
+///
+/// The assigned ids have the highest bit set, from `0x8000_0000` to `u32::MAX`. Messages sent
+/// without a `Requester` must use ids below `0x8000_0000`, otherwise their responses could be
+/// taken for the response of a request.
+#[derive(Clone)]
+pub struct Requester {
+    connection: ConnectionId,
+    sender: Sender<Request>,
+    timeout: Duration,
+}
+
+impl Request {
+    pub(crate) fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+}
+
+impl Requester {
+    pub(crate) fn new(
+        connection: ConnectionId,
+        sender: Sender<Request>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            connection,
+            sender,
+            timeout,
+        }
+    }
+
+    pub async fn request(
+        &self,
+        message: PoolMessages<'static>,
+    ) -> Result<PoolMessages<'static>, RequestError> {
+        if response_types(&message).is_none() {
+            return Err(RequestError::NotARequest);
+        }
+        let (respond_to, response) = oneshot::channel();
+        let request = Request {
+            connection: self.connection,
+            message,
+            respond_to,
+        };
+        let response = async {
+            self.sender
+                .send(request)
+                .await
+                .map_err(|_| RequestError::ConnectionClosed)?;
+            response.await.map_err(|_| RequestError::ConnectionClosed)
+        };
+        tokio::time::timeout(self.timeout, response)
+            .await
+            .map_err(|_| RequestError::Timeout)?
+    }
+}
+
+/// Send requests to the downstreams connected to a `Server`.
+#[derive(Clone)]
+pub struct ServerRequester {
+    sender: Sender<Request>,
+    timeout: Duration,
+}
+
+impl ServerRequester {
+    pub(crate) fn new(sender: Sender<Request>, timeout: Duration) -> Self {
+        Self { sender, timeout }
+    }
+
+    /// A `Requester` that sends requests to the downstream with id `connection`.
+    pub fn connection(&self, connection: ConnectionId) -> Requester {
+        Requester::new(connection, self.sender.clone(), self.timeout)
+    }
+}
+
+/// First `request_id` assigned to the requests, the lower ids are left to the messages sent
+/// without a `Requester`.
+const FIRST_REQUEST_ID: u32 = 0x8000_0000;
+
+/// The requests sent and still waiting for a response.
+#[derive(Default)]
+pub(crate) struct PendingRequests {
+    inner: StdMutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    next_id: u32,
+    requests: HashMap<(ConnectionId, u32), PendingRequest>,
+}
+
+struct PendingRequest {
+    respond_to: oneshot::Sender<PoolMessages<'static>>,
+    /// The message types of the `Success` and `Error` that answer the request
+    response_types: &'static [MessageType],
+}
+
+impl PendingRequests {
+    /// Assign a `request_id` to the request, returns the connection and the message to send.
+    pub(crate) fn register(&self, request: Request) -> (ConnectionId, PoolMessages<'static>) {
+        let mut pending = self.inner.lock().expect("PendingRequests mutex poisoned");
+        // Requests that timed out have no one waiting for them anymore
+        pending
+            .requests
+            .retain(|_, request| !request.respond_to.is_closed());
+        let mut id = pending.next_id.max(FIRST_REQUEST_ID);
+        while pending.requests.contains_key(&(request.connection, id)) {
+            id = next_request_id(id);
+        }
+        pending.next_id = next_request_id(id);
+        let pending_request = PendingRequest {
+            respond_to: request.respond_to,
+            response_types: response_types(&request.message).unwrap_or_default(),
+        };
+        pending
+            .requests
+            .insert((request.connection, id), pending_request);
+        let mut message = request.message;
+        set_request_id(&mut message, id);
+        (request.connection, message)
+    }
+
+    /// Return the message to its requester if it is the response to a pending request,
+    /// otherwise give it back. A response with the `request_id` of a pending request but of a
+    /// type that does not answer it is given back and the request keeps waiting.
+    pub(crate) fn on_response(
+        &self,
+        connection: ConnectionId,
+        message: PoolMessages<'static>,
+    ) -> Option<PoolMessages<'static>> {
+        let Some((message_type, id)) = response_type_and_id(&message) else {
+            return Some(message);
+        };
+        let mut pending = self.inner.lock().expect("PendingRequests mutex poisoned");
+        let key = (connection, id);
+        match pending.requests.get(&key) {
+            Some(request) if request.response_types.contains(&message_type) => (),
+            Some(_) => {
+                eprintln!("Message type {message_type} does not answer request {id}");
+                return Some(message);
+            }
+            None => return Some(message),
+        }
+        let request = pending.requests.remove(&key).expect("Request is pending");
+        if request.respond_to.send(message).is_err() {
+            eprintln!("Response to request {id} received after the timeout");
+        }
+        None
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner
+            .lock()
+            .expect("PendingRequests mutex poisoned")
+            .requests
+            .is_empty()
+    }
+
+    /// Drop the requests sent to `connection`, their requesters get `ConnectionClosed`.
+    pub(crate) fn remove_connection(&self, connection: ConnectionId) {
+        self.inner
+            .lock()
+            .expect("PendingRequests mutex poisoned")
+            .requests
+            .retain(|(c, _), _| *c != connection);
+    }
+}
+
+/// The id after `id`, wrapping back to `FIRST_REQUEST_ID`.
+fn next_request_id(id: u32) -> u32 {
+    id.checked_add(1).unwrap_or(FIRST_REQUEST_ID)
+}
+
+/// The message type and the `request_id` of the `Success` and `Error` messages.
+fn response_type_and_id(message: &PoolMessages) -> Option<(MessageType, u32)> {
+    match message {
+        PoolMessages::Mining(m) => match m {
+            Mining::OpenStandardMiningChannelSuccess(m) => Some((
+                MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+                u32_as_ref(&m.request_id),
+            )),
+            Mining::OpenExtendedMiningChannelSuccess(m) => Some((
+                MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+                m.request_id,
+            )),
+            Mining::OpenMiningChannelError(m) => {
+                Some((MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR, m.request_id))
+            }
+            Mining::SetCustomMiningJobSuccess(m) => {
+                Some((MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS, m.request_id))
+            }
+            Mining::SetCustomMiningJobError(m) => {
+                Some((MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR, m.request_id))
+            }
+            _ => None,
+        },
+        PoolMessages::JobDeclaration(m) => match m {
+            JobDeclaration::AllocateMiningJobTokenSuccess(m) => {
+                Some((MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS, m.request_id))
+            }
+            JobDeclaration::DeclareMiningJobSuccess(m) => {
+                Some((MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS, m.request_id))
+            }
+            JobDeclaration::DeclareMiningJobError(m) => {
+                Some((MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR, m.request_id))
+            }
+            JobDeclaration::IdentifyTransactionsSuccess(m) => {
+                Some((MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS, m.request_id))
+            }
+            JobDeclaration::ProvideMissingTransactionsSuccess(m) => Some((
+                MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS,
+                m.request_id,
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The message types of the `Success` and `Error` that answer `message`, `None` if it is not a
+/// request.
+fn response_types(message: &PoolMessages) -> Option<&'static [MessageType]> {
+    let types: &'static [MessageType] = match message {
+        PoolMessages::Mining(m) => match m {
+            Mining::OpenStandardMiningChannel(_) => &[
+                MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+                MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR,
+            ],
+            Mining::OpenExtendedMiningChannel(_) => &[
+                MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+                MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR,
+            ],
+            Mining::SetCustomMiningJob(_) => &[
+                MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS,
+                MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR,
+            ],
+            _ => return None,
+        },
+        PoolMessages::JobDeclaration(m) => match m {
+            JobDeclaration::AllocateMiningJobToken(_) => {
+                &[MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS]
+            }
+            JobDeclaration::DeclareMiningJob(_) => &[
+                MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
+                MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
+            ],
+            JobDeclaration::IdentifyTransactions(_) => {
+                &[MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS]
+            }
+            JobDeclaration::ProvideMissingTransactions(_) => {
+                &[MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS]
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(types)
+}
+
+fn set_request_id(message: &mut PoolMessages, id: u32) {
+    match message {
+        PoolMessages::Mining(m) => match m {
+            Mining::OpenStandardMiningChannel(m) => m.request_id = id.into(),
+            Mining::OpenExtendedMiningChannel(m) => m.request_id = id,
+            Mining::SetCustomMiningJob(m) => m.request_id = id,
+            _ => (),
+        },
+        PoolMessages::JobDeclaration(m) => match m {
+            JobDeclaration::AllocateMiningJobToken(m) => m.request_id = id,
+            JobDeclaration::DeclareMiningJob(m) => m.request_id = id,
+            JobDeclaration::IdentifyTransactions(m) => m.request_id = id,
+            JobDeclaration::ProvideMissingTransactions(m) => m.request_id = id,
+            _ => (),
+        },
+        _ => (),
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+pub(crate) fn u32_as_ref(value: &binary_sv2::U32AsRef) -> u32 {
+    value.as_u32()
+}
+
+#[cfg(feature = "with_serde")]
+pub(crate) fn u32_as_ref(value: &u32) -> u32 {
+    *value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roles_logic_sv2::{
+        job_declaration_sv2::{AllocateMiningJobToken, AllocateMiningJobTokenSuccess},
+        mining_sv2::OpenMiningChannelError,
+    };
+
+    fn request(connection: ConnectionId) -> (Request, oneshot::Receiver<PoolMessages<'static>>) {
+        let (respond_to, response) = oneshot::channel();
+        let message = PoolMessages::JobDeclaration(JobDeclaration::AllocateMiningJobToken(
+            AllocateMiningJobToken {
+                user_identifier: "miner".to_string().try_into().unwrap(),
+                request_id: 0,
+            },
+        ));
+        (
+            Request {
+                connection,
+                message,
+                respond_to,
+            },
+            response,
+        )
+    }
+
+    fn request_id(message: &PoolMessages) -> u32 {
+        match message {
+            PoolMessages::JobDeclaration(JobDeclaration::AllocateMiningJobToken(m)) => m.request_id,
+            _ => panic!("Not an AllocateMiningJobToken"),
+        }
+    }
+
+    fn success(request_id: u32) -> PoolMessages<'static> {
+        PoolMessages::JobDeclaration(JobDeclaration::AllocateMiningJobTokenSuccess(
+            AllocateMiningJobTokenSuccess {
+                request_id,
+                mining_job_token: vec![1].try_into().unwrap(),
+                coinbase_output_max_additional_size: 0,
+                coinbase_output: vec![].try_into().unwrap(),
+                async_mining_allowed: true,
+            },
+        ))
+    }
+
+    fn error(request_id: u32) -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::OpenMiningChannelError(OpenMiningChannelError {
+            request_id,
+            error_code: "unknown-user".to_string().try_into().unwrap(),
+        }))
+    }
+
+    #[test]
+    fn ids_are_assigned_from_the_reserved_range() {
+        let pending = PendingRequests::default();
+        let (first, _first) = request(1);
+        let (second, _second) = request(1);
+        let (connection, first) = pending.register(first);
+        assert_eq!(connection, 1);
+        assert_eq!(request_id(&first), FIRST_REQUEST_ID);
+        assert_eq!(
+            request_id(&pending.register(second).1),
+            FIRST_REQUEST_ID + 1
+        );
+    }
+
+    #[test]
+    fn ids_wrap_to_the_reserved_range() {
+        let pending = PendingRequests::default();
+        pending.inner.lock().unwrap().next_id = u32::MAX;
+        let (last, _last) = request(1);
+        let (next, _next) = request(1);
+        assert_eq!(request_id(&pending.register(last).1), u32::MAX);
+        assert_eq!(request_id(&pending.register(next).1), FIRST_REQUEST_ID);
+    }
+
+    #[test]
+    fn pending_ids_are_not_reused() {
+        let pending = PendingRequests::default();
+        let (first, _first) = request(1);
+        pending.register(first);
+        pending.inner.lock().unwrap().next_id = FIRST_REQUEST_ID;
+        let (second, _second) = request(1);
+        assert_eq!(
+            request_id(&pending.register(second).1),
+            FIRST_REQUEST_ID + 1
+        );
+    }
+
+    #[test]
+    fn response_goes_to_its_requester() {
+        let pending = PendingRequests::default();
+        let (request, mut response) = request(1);
+        let id = request_id(&pending.register(request).1);
+
+        // Same id from another connection, an id that has not been assigned, and a response to
+        // another kind of request
+        assert!(pending.on_response(2, success(id)).is_some());
+        assert!(pending.on_response(1, success(id + 1)).is_some());
+        assert!(pending.on_response(1, error(id)).is_some());
+        assert!(response.try_recv().is_err());
+
+        assert!(pending.on_response(1, success(id)).is_none());
+        assert!(matches!(
+            response.try_recv().unwrap(),
+            PoolMessages::JobDeclaration(JobDeclaration::AllocateMiningJobTokenSuccess(m))
+                if m.request_id == id
+        ));
+        assert!(pending.is_empty());
+        // Only one response per request
+        assert!(pending.on_response(1, success(id)).is_some());
+    }
+
+    #[test]
+    fn messages_without_request_id_are_given_back() {
+        let pending = PendingRequests::default();
+        let (request, _response) = request(1);
+        let (_, message) = pending.register(request);
+        assert!(pending.on_response(1, message).is_some());
+    }
+
+    #[test]
+    fn timed_out_and_closed_requests_are_dropped() {
+        let pending = PendingRequests::default();
+        let (timed_out, response) = request(1);
+        pending.register(timed_out);
+        drop(response);
+        let (closed, mut response) = request(2);
+        pending.register(closed);
+        let (next, _next) = request(3);
+        pending.register(next);
+        assert_eq!(pending.inner.lock().unwrap().requests.len(), 2);
+
+        pending.remove_connection(2);
+        assert!(response.try_recv().is_err());
+        assert_eq!(pending.inner.lock().unwrap().requests.len(), 1);
+    }
+}
@@ -1,19 +1,57 @@
 use codec_sv2::{HandshakeRole, Responder};
 pub use const_sv2;
-use demand_sv2_connection::noise_connection_tokio::Connection;
 use key_utils::{Error as KeyUtilsError, Secp256k1PublicKey, Secp256k1SecretKey};
 pub use roles_logic_sv2;
 pub use roles_logic_sv2::parsers::PoolMessages;
+use roles_logic_sv2::{
+    common_messages_sv2::{
+        Protocol, SetupConnection, SetupConnectionError, SetupConnectionSuccess,
+    },
+    mining_sv2::OpenMiningChannelError,
+    parsers::{CommonMessages, Mining},
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
 use tokio::{
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs, UnixStream},
     select,
     sync::mpsc::{channel, Receiver, Sender},
+    task::AbortHandle,
+    time::timeout,
 };
 
-use crate::message_channel::MessageChannel;
+use crate::authorization::{
+    error_code, Authorization, Authorizer, DynAuthorizer, Identities, Peer,
+};
+use crate::connection::{
+    is_loopback, noise_connection, peer_name, plain_connection, recv_within, Timeouts,
+};
+use crate::extension::{
+    ExtensionMessage, ExtensionNegotiator, ExtensionRegistry, RawChannel, RawFrame, RawHandler,
+};
+use crate::handler::{
+    push_handler, AnyHandler, Dispatcher, Handler, MessageHandler, OwnerConflict, TypedMessage,
+};
+use crate::inbound::{Inbound, InboundSender, Origins};
+use crate::keys::{AuthorityKeyPair, KeysError};
+use crate::message_channel::{
+    message_from_frame, message_type, recv_or_pending, ConnectionId, DropCounter, FrameError,
+    HandlerConfig, HandlerKey, HandlerReceiver, HandlerSender, Interceptor, InvalidFrame,
+    InvalidFramePolicy, InvalidFrames, MessageChannel, MessageChannelError, MessageType, Observer,
+    Outcome, Overflow, RoutedMessage, RoutedTyped, Typed,
+};
+use crate::request::{u32_as_ref, PendingRequests, Request};
+use crate::shutdown::ShutdownSignal;
 use crate::Frame_;
 use crate::Remote;
 use crate::StdFrame;
+use crate::{ServerRequester, ShutdownHandle};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ServerError {
@@ -21,75 +59,911 @@ pub enum ServerError {
     DownstreamClosed,
     DownstreamClosedDuringSetupSv2Connection,
     ImpossibleSetupSv2ConnectionWithUpstream,
+    ListenerClosed,
+    ExpectedSetupConnection,
+    SetupConnectionRejected,
+    InvalidFrame(FrameError),
+    HandlerDropped(MessageType),
+    /// The channel of a handler with `OverflowPolicy::Disconnect` is full
+    HandlerOverflow(MessageType),
+    InterceptorDropped,
+    /// A frame from the extension sender has a payload of 2^24 bytes or more
+    PayloadTooLong(MessageType),
+    /// The downstream did not send the SetupConnection in time
+    SetupTimeout,
+    /// Nothing has been received from the downstream for longer than the idle timeout
+    IdleTimeout,
 }
 
-pub struct Server {
-    from_client: Receiver<Frame_>,
+/// Where a message sent with a routed message sender must go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Connection(ConnectionId),
+    Broadcast,
+}
+
+#[derive(Clone, Copy)]
+struct AuthorityKeys {
+    pub_key: Secp256k1PublicKey,
+    sec_key: Secp256k1SecretKey,
+    cert_validity: u64,
+}
+
+impl AuthorityKeys {
+    fn responder(&self) -> Box<Responder> {
+        Responder::from_authority_kp(
+            &self.pub_key.into_bytes(),
+            &self.sec_key.into_bytes(),
+            std::time::Duration::from_secs(self.cert_validity),
+        )
+        .expect("invalid key pair")
+    }
+}
+
+struct Acceptor {
+    listener: TcpListener,
+    /// `None` when the listener is unencrypted
+    keys: Option<AuthorityKeys>,
+    timeouts: Timeouts,
+    to_dispatcher: Sender<(ConnectionId, SystemTime, Frame_)>,
+    setup_connection: Arc<SetupConnectionNegotiator>,
+}
+
+/// What the server accepts in the `SetupConnection` sent by every downstream as first message.
+struct SetupConnectionNegotiator {
+    protocol: Option<Protocol>,
+    min_version: u16,
+    max_version: u16,
+    supported_flags: u32,
+    server_flags: u32,
+    observer: Option<Observer<(ConnectionId, SetupConnection<'static>)>>,
+    /// Maximum time to wait for the SetupConnection
+    timeout: Duration,
+    authorizer: Option<Arc<dyn DynAuthorizer>>,
+}
+
+impl SetupConnectionNegotiator {
+    fn check(
+        &self,
+        setup_connection: &SetupConnection<'static>,
+    ) -> Result<SetupConnectionSuccess, SetupConnectionError<'static>> {
+        let error = |flags: u32, error_code: &str| SetupConnectionError {
+            flags,
+            error_code: error_code
+                .to_string()
+                .try_into()
+                .expect("Error codes are shorter than 255 bytes"),
+        };
+        if let Some(protocol) = self.protocol {
+            if protocol != setup_connection.protocol {
+                return Err(error(0, "unsupported-protocol"));
+            }
+        }
+        let used_version = setup_connection
+            .get_version(self.min_version, self.max_version)
+            .ok_or_else(|| error(0, "protocol-version-mismatch"))?;
+        let unsupported_flags = setup_connection.flags & !self.supported_flags;
+        if unsupported_flags != 0 {
+            return Err(error(unsupported_flags, "unsupported-feature-flags"));
+        }
+        Ok(SetupConnectionSuccess {
+            used_version,
+            flags: self.server_flags,
+        })
+    }
+
+    /// Ask the authorizer, if any, whether the downstream can connect.
+    async fn authorize(
+        &self,
+        peer: Peer,
+        setup_connection: &SetupConnection<'static>,
+    ) -> Result<Option<String>, SetupConnectionError<'static>> {
+        let Some(authorizer) = &self.authorizer else {
+            return Ok(None);
+        };
+        match authorizer
+            .setup_connection(peer, setup_connection.clone())
+            .await
+        {
+            Authorization::Accept => Ok(None),
+            Authorization::AcceptAs(identity) => Ok(Some(identity)),
+            Authorization::Reject(code) => Err(SetupConnectionError {
+                flags: 0,
+                error_code: error_code(code),
+            }),
+        }
+    }
+
+    /// Wait for the `SetupConnection` of the downstream and answer it with a
+    /// `SetupConnection.Success` or a `SetupConnection.Error`. The accepted `SetupConnection` is
+    /// sent to the setup connection handler before any other message from the downstream is
+    /// dispatched, after the identity given by the authorizer has been set.
+    async fn negotiate(
+        &self,
+        id: ConnectionId,
+        address: Option<SocketAddr>,
+        from_client: &mut Receiver<Frame_>,
+        to_client: &Sender<Frame_>,
+        identities: &Identities,
+    ) -> Result<(SetupConnection<'static>, SetupConnectionSuccess), ServerError> {
+        let mut frame = timeout(self.timeout, from_client.recv())
+            .await
+            .map_err(|_| ServerError::SetupTimeout)?
+            .ok_or(ServerError::DownstreamClosedDuringSetupSv2Connection)?;
+        let setup_connection = match message_from_frame(&mut frame, Remote::Client, None)
+            .map_err(ServerError::InvalidFrame)?
+        {
+            (_, PoolMessages::Common(CommonMessages::SetupConnection(m))) => m,
+            (mt, _) => {
+                eprintln!("Expected SetupConnection from downstream {id}, received: {mt}");
+                return Err(ServerError::ExpectedSetupConnection);
+            }
+        };
+        let result = match self.check(&setup_connection) {
+            Ok(success) => {
+                let peer = Peer {
+                    connection: id,
+                    address,
+                    identity: None,
+                };
+                self.authorize(peer, &setup_connection)
+                    .await
+                    .map(|identity| (success, identity))
+            }
+            Err(error) => Err(error),
+        };
+        let reply = match &result {
+            Ok((success, _)) => {
+                PoolMessages::Common(CommonMessages::SetupConnectionSuccess(*success))
+            }
+            Err(error) => PoolMessages::Common(CommonMessages::SetupConnectionError(error.clone())),
+        };
+        let frame: StdFrame = reply
+            .try_into()
+            .expect("A message can always be converted in a frame");
+        if to_client.send(frame.into()).await.is_err() {
+            return Err(ServerError::DownstreamClosedDuringSetupSv2Connection);
+        }
+        let success = match result {
+            Ok((success, Some(identity))) => {
+                identities.set(id, identity);
+                success
+            }
+            Ok((success, None)) => success,
+            Err(_) => return Err(ServerError::SetupConnectionRejected),
+        };
+        if let Some(observer) = &self.observer {
+            observer
+                .send((id, setup_connection.clone()), "SetupConnection")
+                .await;
+        }
+        Ok((setup_connection, success))
+    }
+}
+
+enum Downstream {
+    Single {
+        from_client: Receiver<Frame_>,
+        to_client: Sender<Frame_>,
+        /// `None` when the downstream is not connected with TCP
+        address: Option<SocketAddr>,
+    },
+    Listener {
+        listener: TcpListener,
+        keys: Option<AuthorityKeys>,
+    },
+}
+
+struct DownstreamConnection {
     to_client: Sender<Frame_>,
-    handlers: Vec<MessageChannel>,
+    protocol: Protocol,
+    address: Option<SocketAddr>,
+    abort_handles: Vec<AbortHandle>,
+}
+
+/// The downstreams connected to a `Server`. When the server is not listening there is only one
+/// downstream and closing it closes the server.
+struct Downstreams {
+    connections: StdMutex<HashMap<ConnectionId, DownstreamConnection>>,
+    listening: bool,
+    requests: PendingRequests,
+    identities: Identities,
+    origins: Origins,
+}
+
+impl Downstreams {
+    fn insert(
+        &self,
+        id: ConnectionId,
+        to_client: Sender<Frame_>,
+        (setup_connection, success): (SetupConnection<'static>, SetupConnectionSuccess),
+        address: Option<SocketAddr>,
+        abort_handles: Vec<AbortHandle>,
+    ) {
+        let protocol = setup_connection.protocol;
+        self.origins.set_address(id, address);
+        self.origins.set_setup(id, setup_connection);
+        self.origins.set_success(id, success);
+        self.connections
+            .lock()
+            .expect("Downstreams mutex poisoned")
+            .insert(
+                id,
+                DownstreamConnection {
+                    to_client,
+                    protocol,
+                    address,
+                    abort_handles,
+                },
+            );
+    }
+
+    fn remove(&self, id: ConnectionId) -> Option<DownstreamConnection> {
+        self.requests.remove_connection(id);
+        self.identities.remove(id);
+        self.origins.remove(id);
+        self.connections
+            .lock()
+            .expect("Downstreams mutex poisoned")
+            .remove(&id)
+    }
+
+    /// The protocol negotiated with the downstream, `None` if the downstream is not connected.
+    fn protocol(&self, id: ConnectionId) -> Option<Protocol> {
+        self.connections
+            .lock()
+            .expect("Downstreams mutex poisoned")
+            .get(&id)
+            .map(|c| c.protocol)
+    }
+
+    /// The downstream as seen by the authorizer.
+    fn peer(&self, id: ConnectionId) -> Peer {
+        let address = self
+            .connections
+            .lock()
+            .expect("Downstreams mutex poisoned")
+            .get(&id)
+            .and_then(|c| c.address);
+        Peer {
+            connection: id,
+            address,
+            identity: self.identities.get(id),
+        }
+    }
+
+    fn get(&self, destination: Destination) -> Vec<(ConnectionId, Sender<Frame_>)> {
+        let connections = self.connections.lock().expect("Downstreams mutex poisoned");
+        match destination {
+            Destination::Connection(id) => connections
+                .get(&id)
+                .map(|c| vec![(id, c.to_client.clone())])
+                .unwrap_or_default(),
+            Destination::Broadcast => connections
+                .iter()
+                .map(|(id, c)| (*id, c.to_client.clone()))
+                .collect(),
+        }
+    }
+
+    async fn send_frame(&self, id: ConnectionId, frame: Frame_) -> Result<(), ServerError> {
+        match self.get(Destination::Connection(id)).pop() {
+            Some((_, to_client)) => {
+                if to_client.send(frame).await.is_err() {
+                    return self.close(id, ServerError::DownstreamClosed);
+                }
+                Ok(())
+            }
+            None => self.close(id, ServerError::DownstreamClosed),
+        }
+    }
+
+    async fn send_message(
+        &self,
+        destination: Destination,
+        message: PoolMessages<'static>,
+    ) -> Result<(), ServerError> {
+        // The answers to the open channel messages are only broadcast when there is one
+        // downstream
+        match destination {
+            Destination::Connection(id) => self.identities.on_sent(id, &message),
+            Destination::Broadcast if !self.listening => self.identities.on_sent(0, &message),
+            Destination::Broadcast => (),
+        }
+        self.send_to(destination, || {
+            let frame: StdFrame = message
+                .clone()
+                .try_into()
+                .expect("A message can always be converted in a frame");
+            frame.into()
+        })
+        .await
+    }
+
+    async fn send_raw_frame(
+        &self,
+        destination: Destination,
+        frame: RawFrame,
+    ) -> Result<(), ServerError> {
+        if frame.is_too_long() {
+            return Err(ServerError::PayloadTooLong(frame.message_type));
+        }
+        self.send_to(destination, || {
+            let frame = frame.clone().into_frame();
+            frame.expect("The length has been checked").into()
+        })
+        .await
+    }
+
+    /// Send to every downstream of `destination` a frame built by `frame`.
+    async fn send_to(
+        &self,
+        destination: Destination,
+        frame: impl Fn() -> Frame_,
+    ) -> Result<(), ServerError> {
+        let to_clients = self.get(destination);
+        if to_clients.is_empty() && !self.listening {
+            return Err(ServerError::DownstreamClosed);
+        }
+        for (id, to_client) in to_clients {
+            if to_client.send(frame()).await.is_err() {
+                self.close(id, ServerError::DownstreamClosed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every connection without aborting it, so that the noise connections can flush the
+    /// frames already sent before closing.
+    fn close_all(&self) {
+        let connections: Vec<ConnectionId> = self
+            .connections
+            .lock()
+            .expect("Downstreams mutex poisoned")
+            .keys()
+            .copied()
+            .collect();
+        for id in connections {
+            self.remove(id);
+        }
+    }
+
+    /// Close the connection with the downstream, when the server is not listening this means
+    /// closing the server with `error`.
+    fn close(&self, id: ConnectionId, error: ServerError) -> Result<(), ServerError> {
+        if let Some(connection) = self.remove(id) {
+            for handle in connection.abort_handles {
+                handle.abort();
+            }
+        }
+        if self.listening {
+            eprintln!("Downstream {id} closed: {error:?}");
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+pub struct Server {
+    downstream: Downstream,
+    handlers: Dispatcher,
     messages_to_send: Option<Receiver<PoolMessages<'static>>>,
+    routed_messages_to_send: Option<Receiver<(Destination, PoolMessages<'static>)>>,
+    extensions_to_send: Option<Receiver<(Destination, RawFrame)>>,
+    interceptors: Vec<Interceptor>,
+    requests: Option<Receiver<Request>>,
+    invalid_frames: InvalidFrames,
+    shutdown: ShutdownSignal,
+    setup_connection: Arc<SetupConnectionNegotiator>,
+    timeouts: Timeouts,
+    origins: Origins,
 }
 impl Server {
     pub async fn start(self) -> Result<(), ServerError> {
-        let mut client_handlers = vec![];
-        let mut server_handlers = vec![];
-        for handler in self.handlers {
-            match handler.expect_from {
-                Remote::Client => client_handlers.push(handler),
-                Remote::Server => server_handlers.push(handler),
+        let (to_dispatcher, from_downstreams) = channel(10);
+        let (downstreams, acceptor) = match self.downstream {
+            Downstream::Single {
+                mut from_client,
+                to_client,
+                address,
+            } => {
+                let downstreams = Arc::new(Downstreams {
+                    connections: StdMutex::new(HashMap::new()),
+                    listening: false,
+                    requests: PendingRequests::default(),
+                    identities: self.origins.identities().clone(),
+                    origins: self.origins.clone(),
+                });
+                let setup_connection = self
+                    .setup_connection
+                    .negotiate(
+                        0,
+                        address,
+                        &mut from_client,
+                        &to_client,
+                        &downstreams.identities,
+                    )
+                    .await?;
+                downstreams.insert(0, to_client, setup_connection, address, vec![]);
+                // The idle timeout of the only downstream closes the server, it is checked by
+                // `recv_from_down`
+                tokio::spawn(Self::forward(
+                    0,
+                    from_client,
+                    to_dispatcher,
+                    downstreams.clone(),
+                    None,
+                ));
+                (downstreams, None)
+            }
+            Downstream::Listener { listener, keys } => {
+                let downstreams = Arc::new(Downstreams {
+                    connections: StdMutex::new(HashMap::new()),
+                    listening: true,
+                    requests: PendingRequests::default(),
+                    identities: self.origins.identities().clone(),
+                    origins: self.origins.clone(),
+                });
+                (
+                    downstreams,
+                    Some(Acceptor {
+                        listener,
+                        keys,
+                        timeouts: self.timeouts,
+                        to_dispatcher,
+                        setup_connection: self.setup_connection.clone(),
+                    }),
+                )
             }
+        };
+        tokio::try_join!(
+            Self::accept(acceptor, downstreams.clone(), &self.shutdown),
+            Self::send_to_down(
+                self.messages_to_send,
+                self.routed_messages_to_send,
+                self.extensions_to_send,
+                self.requests,
+                self.interceptors,
+                downstreams.clone(),
+                &self.shutdown,
+            ),
+            Self::recv_from_down(
+                from_downstreams,
+                downstreams.clone(),
+                self.handlers,
+                &self.invalid_frames,
+                self.setup_connection.authorizer.as_deref(),
+                self.timeouts.idle,
+                &self.shutdown,
+            ),
+        )?;
+        for message in self.shutdown.goodbye(Remote::Client) {
+            downstreams
+                .send_message(Destination::Broadcast, message)
+                .await?;
+        }
+        downstreams.close_all();
+        Ok(())
+    }
+
+    async fn accept(
+        acceptor: Option<Acceptor>,
+        downstreams: Arc<Downstreams>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<(), ServerError> {
+        let Some(Acceptor {
+            listener,
+            keys,
+            timeouts,
+            to_dispatcher,
+            setup_connection,
+        }) = acceptor
+        else {
+            shutdown.requested().await;
+            return Ok(());
+        };
+        let mut next_id: ConnectionId = 0;
+        loop {
+            let accepted = select! {
+                _ = shutdown.requested() => return Ok(()),
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient_accept_error(&e) => {
+                    eprintln!("Impossible to accept downstream connection, retrying: {e}");
+                    select! {
+                        _ = shutdown.requested() => return Ok(()),
+                        _ = tokio::time::sleep(ACCEPT_RETRY_DELAY) => continue,
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Impossible to accept downstream connection: {e}");
+                    return Err(ServerError::ListenerClosed);
+                }
+            };
+            let id = next_id;
+            next_id = next_id.wrapping_add(1);
+            let to_dispatcher = to_dispatcher.clone();
+            let downstreams = downstreams.clone();
+            let setup_connection = setup_connection.clone();
+            // Every downstream completes its handshake in its own task, a downstream that does
+            // not complete it only holds its own task until the handshake timeout
+            tokio::spawn(async move {
+                let connection = match keys {
+                    Some(keys) => {
+                        let responder = HandshakeRole::Responder(keys.responder());
+                        let connection = timeout(
+                            timeouts.handshake,
+                            noise_connection(stream, peer.to_string(), responder),
+                        )
+                        .await;
+                        match connection {
+                            Ok(Some(connection)) => connection,
+                            Ok(None) => return,
+                            Err(_) => {
+                                eprintln!("Handshake with downstream {peer} timed out");
+                                return;
+                            }
+                        }
+                    }
+                    None => plain_connection(stream, peer.to_string()),
+                };
+                let (mut from_client, to_client, [recv_handle, send_handle]) = connection;
+                match setup_connection
+                    .negotiate(
+                        id,
+                        Some(peer),
+                        &mut from_client,
+                        &to_client,
+                        &downstreams.identities,
+                    )
+                    .await
+                {
+                    Ok(setup_connection) => {
+                        downstreams.insert(
+                            id,
+                            to_client,
+                            setup_connection,
+                            Some(peer),
+                            vec![recv_handle, send_handle],
+                        );
+                        Self::forward(id, from_client, to_dispatcher, downstreams, timeouts.idle)
+                            .await;
+                    }
+                    // Dropping the channels let the noise connection flush the
+                    // SetupConnection.Error before closing
+                    Err(e) => {
+                        eprintln!("Impossible to setup connection with {peer}: {e:?}");
+                    }
+                }
+            });
         }
-        if let Some(messages_to_send) = self.messages_to_send {
-            select! {
-                r = Self::send_to_down(messages_to_send, self.to_client.clone()) => r,
-                r = Self::recv_from_down(self.from_client, self.to_client, server_handlers) => r,
+    }
+
+    async fn forward(
+        id: ConnectionId,
+        mut from_client: Receiver<Frame_>,
+        to_dispatcher: Sender<(ConnectionId, SystemTime, Frame_)>,
+        downstreams: Arc<Downstreams>,
+        idle: Option<Duration>,
+    ) {
+        loop {
+            match recv_within(&mut from_client, idle).await {
+                Ok(Some(frame)) => {
+                    let received_at = SystemTime::now();
+                    if to_dispatcher.send((id, received_at, frame)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    let _ = downstreams.close(id, ServerError::IdleTimeout);
+                    return;
+                }
+            }
+        }
+        downstreams.remove(id);
+    }
+
+    /// Ask the authorizer whether the downstream can open the channel, a rejected channel is
+    /// answered with `OpenMiningChannel.Error` and its message is not given back.
+    async fn authorize_channel(
+        id: ConnectionId,
+        message: PoolMessages<'static>,
+        downstreams: &Downstreams,
+        authorizer: &dyn DynAuthorizer,
+    ) -> Result<Option<PoolMessages<'static>>, ServerError> {
+        let peer = downstreams.peer(id);
+        let (request_id, authorization) = match &message {
+            PoolMessages::Mining(Mining::OpenStandardMiningChannel(m)) => (
+                u32_as_ref(&m.request_id),
+                authorizer
+                    .open_standard_mining_channel(peer, m.clone())
+                    .await,
+            ),
+            PoolMessages::Mining(Mining::OpenExtendedMiningChannel(m)) => (
+                m.request_id,
+                authorizer
+                    .open_extended_mining_channel(peer, m.clone())
+                    .await,
+            ),
+            _ => return Ok(Some(message)),
+        };
+        match authorization {
+            Authorization::Accept => Ok(Some(message)),
+            Authorization::AcceptAs(identity) => {
+                downstreams.identities.set_request(id, request_id, identity);
+                Ok(Some(message))
+            }
+            Authorization::Reject(code) => {
+                let error = OpenMiningChannelError {
+                    request_id,
+                    error_code: error_code(code),
+                };
+                downstreams
+                    .send_message(
+                        Destination::Connection(id),
+                        PoolMessages::Mining(Mining::OpenMiningChannelError(error)),
+                    )
+                    .await?;
+                Ok(None)
             }
-        } else {
-            Self::recv_from_down(self.from_client, self.to_client, server_handlers).await
         }
     }
 
+    /// Send to the downstreams the messages from the plain sender, that go to every downstream,
+    /// and the ones from the routed sender.
     async fn send_to_down(
-        mut recv: Receiver<PoolMessages<'static>>,
-        send: Sender<Frame_>,
+        mut messages_to_send: Option<Receiver<PoolMessages<'static>>>,
+        mut routed_messages_to_send: Option<Receiver<(Destination, PoolMessages<'static>)>>,
+        mut extensions_to_send: Option<Receiver<(Destination, RawFrame)>>,
+        mut requests: Option<Receiver<Request>>,
+        mut interceptors: Vec<Interceptor>,
+        downstreams: Arc<Downstreams>,
+        shutdown: &ShutdownSignal,
     ) -> Result<(), ServerError> {
-        while let Some(message) = recv.recv().await {
-            let frame: StdFrame = message
-                .try_into()
-                .expect("A message can always be converted in a frame");
-            if send.send(frame.into()).await.is_err() {
-                return Err(ServerError::DownstreamClosed);
-            }
+        loop {
+            let received = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Flush the messages already queued
+                    while let Some(Ok(m)) = messages_to_send.as_mut().map(Receiver::try_recv) {
+                        Self::send_message(Destination::Broadcast, m, &mut interceptors, &downstreams).await?;
+                    }
+                    while let Some(Ok((destination, m))) = routed_messages_to_send.as_mut().map(Receiver::try_recv) {
+                        Self::send_message(destination, m, &mut interceptors, &downstreams).await?;
+                    }
+                    while let Some(Ok((destination, frame))) = extensions_to_send.as_mut().map(Receiver::try_recv) {
+                        downstreams.send_raw_frame(destination, frame).await?;
+                    }
+                    return Ok(());
+                }
+                m = recv_or_pending(&mut messages_to_send) => m.map(|m| (Destination::Broadcast, m)),
+                m = recv_or_pending(&mut routed_messages_to_send) => m,
+                f = recv_or_pending(&mut extensions_to_send) => {
+                    let (destination, frame) = f.ok_or(ServerError::MessagesToSendSenderDropped)?;
+                    downstreams.send_raw_frame(destination, frame).await?;
+                    continue;
+                }
+                r = recv_or_pending(&mut requests) => match r {
+                    // Dropping the request tells the requester that the downstream is closed
+                    Some(request) if downstreams.protocol(request.connection()).is_none() => continue,
+                    Some(request) => {
+                        let (id, message) = downstreams.requests.register(request);
+                        Some((Destination::Connection(id), message))
+                    }
+                    None => {
+                        // Every requester has been dropped
+                        requests = None;
+                        continue;
+                    }
+                },
+            };
+            let Some((destination, message)) = received else {
+                return Err(ServerError::MessagesToSendSenderDropped);
+            };
+            Self::send_message(destination, message, &mut interceptors, &downstreams).await?;
         }
-        Err(ServerError::MessagesToSendSenderDropped)
+    }
+
+    async fn send_message(
+        destination: Destination,
+        message: PoolMessages<'static>,
+        interceptors: &mut [Interceptor],
+        downstreams: &Downstreams,
+    ) -> Result<(), ServerError> {
+        let message = Interceptor::intercept(interceptors, message)
+            .await
+            .ok_or(ServerError::InterceptorDropped)?;
+        downstreams.send_message(destination, message).await
     }
 
     async fn recv_from_down(
-        mut recv: Receiver<Frame_>,
-        send: Sender<Frame_>,
-        mut handlers: Vec<MessageChannel>,
+        mut recv: Receiver<(ConnectionId, SystemTime, Frame_)>,
+        downstreams: Arc<Downstreams>,
+        mut handlers: Dispatcher,
+        invalid_frames: &InvalidFrames,
+        authorizer: Option<&dyn DynAuthorizer>,
+        idle: Option<Duration>,
+        shutdown: &ShutdownSignal,
     ) -> Result<(), ServerError> {
-        while let Some(mut frame) = recv.recv().await {
-            for handler in handlers.iter_mut() {
-                if let Some(frame) = handler.on_message(&mut frame).await {
-                    if send.send(frame).await.is_err() {
-                        return Err(ServerError::DownstreamClosed);
-                    };
+        // With a listener the idle timeout is checked for each downstream by `forward`
+        let idle = idle.filter(|_| !downstreams.listening);
+        loop {
+            let (id, received_at, frame) = select! {
+                biased;
+                _ = shutdown.requested() => {
+                    // Dispatch the frames already received
+                    while let Ok((id, received_at, frame)) = recv.try_recv() {
+                        Self::on_frame(id, received_at, frame, &downstreams, &mut handlers, invalid_frames, authorizer).await?;
+                    }
+                    return Ok(());
+                }
+                received = recv_within(&mut recv, idle) => received
+                    .map_err(|_| ServerError::IdleTimeout)?
+                    .ok_or(ServerError::DownstreamClosed)?,
+            };
+            Self::on_frame(
+                id,
+                received_at,
+                frame,
+                &downstreams,
+                &mut handlers,
+                invalid_frames,
+                authorizer,
+            )
+            .await?;
+        }
+    }
+
+    async fn on_frame(
+        id: ConnectionId,
+        received_at: SystemTime,
+        mut frame: Frame_,
+        downstreams: &Downstreams,
+        handlers: &mut Dispatcher,
+        invalid_frames: &InvalidFrames,
+        authorizer: Option<&dyn DynAuthorizer>,
+    ) -> Result<(), ServerError> {
+        let Some(protocol) = downstreams.protocol(id) else {
+            return Ok(());
+        };
+        let open_channel = matches!(
+            message_type(&frame),
+            Ok(Some(
+                const_sv2::MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL
+                    | const_sv2::MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL
+            ))
+        );
+        let mut dispatch = handlers.dispatch(id, received_at, Some(protocol), &mut frame);
+        if let (Some(authorizer), true) = (authorizer, open_channel) {
+            match dispatch.take_message() {
+                Ok(Some(message)) => {
+                    match Self::authorize_channel(id, message, downstreams, authorizer).await? {
+                        Some(message) => dispatch.put_message(message),
+                        None => return Ok(()),
+                    }
                 }
+                Ok(None) => (),
+                Err(e) => return Self::on_invalid_frame(id, e, downstreams, invalid_frames).await,
             }
         }
-        Err(ServerError::DownstreamClosed)
+        if !downstreams.requests.is_empty() {
+            match dispatch.take_message() {
+                Ok(Some(message)) => match downstreams.requests.on_response(id, message) {
+                    Some(message) => dispatch.put_message(message),
+                    None => return Ok(()),
+                },
+                Ok(None) => (),
+                Err(e) => return Self::on_invalid_frame(id, e, downstreams, invalid_frames).await,
+            }
+        }
+        while let Some(outcome) = dispatch.next().await {
+            match outcome {
+                Ok(Outcome::Replies(replies)) => {
+                    for (id, message) in replies {
+                        downstreams
+                            .send_message(Destination::Connection(id), message)
+                            .await?;
+                    }
+                }
+                Ok(Outcome::Frames(frames)) => {
+                    for (id, frame) in frames {
+                        downstreams.send_frame(id, frame).await?;
+                    }
+                }
+                Ok(_) => (),
+                Err(MessageChannelError::InvalidFrame(e)) => {
+                    return Self::on_invalid_frame(id, e, downstreams, invalid_frames).await;
+                }
+                Err(MessageChannelError::HandlerOverflow(mt)) => {
+                    return downstreams.close(id, ServerError::HandlerOverflow(mt));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
     }
+
+    async fn on_invalid_frame(
+        id: ConnectionId,
+        error: FrameError,
+        downstreams: &Downstreams,
+        invalid_frames: &InvalidFrames,
+    ) -> Result<(), ServerError> {
+        match invalid_frames
+            .on_invalid_frame(id, Remote::Client, error)
+            .await
+        {
+            InvalidFramePolicy::DropFrame => Ok(()),
+            InvalidFramePolicy::CloseConnection => {
+                downstreams.close(id, ServerError::InvalidFrame(error))
+            }
+            InvalidFramePolicy::Abort => Err(ServerError::InvalidFrame(error)),
+        }
+    }
+}
+
+/// Authority keys of the stratum examples, they are public so they must not be used outside of
+/// tests.
+pub(crate) const DEFAULT_PUB_KEY: &str = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72";
+pub(crate) const DEFAULT_SEC_KEY: &str = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n";
+
+/// How long the listener waits before accepting again after a transient error.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Too many open files in the system and in the process, the same on Linux, macOS and the BSDs.
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+
+/// Whether an accept error only concerns the connection being accepted or a temporary lack of
+/// resources, the listener keeps working after it.
+fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::OutOfMemory
+    ) || (cfg!(unix) && matches!(e.raw_os_error(), Some(ENFILE | EMFILE)))
 }
 
 pub struct ServerBuilder {
     from_client: Option<Receiver<Frame_>>,
     to_client: Option<Sender<Frame_>>,
+    client_address: Option<SocketAddr>,
+    listener: Option<TcpListener>,
+    unencrypted_listener: bool,
     server_sec_key: Secp256k1SecretKey,
     server_pub_key: Secp256k1PublicKey,
-    handlers: Vec<MessageChannel>,
+    refuse_default_keys: bool,
+    handlers: Vec<AnyHandler>,
     messages_to_send: Option<Receiver<PoolMessages<'static>>>,
+    routed_messages_to_send: Option<Receiver<(Destination, PoolMessages<'static>)>>,
+    extensions_to_send: Option<Receiver<(Destination, RawFrame)>>,
+    interceptors: Vec<Interceptor>,
     cert_validity: u64,
+    timeouts: Timeouts,
+    invalid_frame_policy: InvalidFramePolicy,
+    invalid_frame_handler: Option<Observer<InvalidFrame>>,
+    protocol: Option<Protocol>,
+    min_version: u16,
+    max_version: u16,
+    supported_flags: u32,
+    server_flags: u32,
+    setup_connection_handler: Option<Observer<(ConnectionId, SetupConnection<'static>)>>,
+    extensions: ExtensionRegistry,
+    extensions_handler: Option<Observer<(ConnectionId, Vec<u16>)>>,
+    requests: Option<(Sender<Request>, Receiver<Request>)>,
+    shutdown: Option<ShutdownHandle>,
+    authorizer: Option<Arc<dyn DynAuthorizer>>,
+    origins: Origins,
 }
 
 #[derive(Debug)]
@@ -100,23 +974,54 @@ pub enum ServerBuilderError {
     TryToAddProtocolAfterAddingSetupConnection,
     TryToAddSetupConnectionAfterAddingProtocol,
     CanNotHaveMoreThan1Client,
+    CanNotHaveMoreThan1Listener,
+    CanNotListenAndHaveAClient,
+    ImpossibleToBindListener,
+    InvalidVersionRange,
+    DefaultKeysNotAllowed,
+    KeyPairMismatch,
+    KeyFileError(KeysError),
+    MissingKeyEnvVar(String),
+    /// A handler with a reply is already registered for a message of this key
+    OwnerAlreadyRegistered(HandlerKey),
+    MessageHandlerAlreadyRegistered,
+    /// Unencrypted connections are only accepted on the loopback interface
+    UnencryptedNotOnLoopback,
+    /// The client did not complete the Noise handshake in time
+    HandshakeTimeout,
 }
 impl ServerBuilder {
     pub fn new() -> Self {
         Self {
             from_client: None,
             to_client: None,
+            client_address: None,
+            listener: None,
+            unencrypted_listener: false,
             cert_validity: 10000,
-            server_pub_key: "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
-                .to_string()
-                .parse()
-                .expect("Invalid default pub key"),
-            server_sec_key: "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
-                .to_string()
-                .parse()
-                .expect("Invalid default sec key"),
+            timeouts: Timeouts::default(),
+            server_pub_key: DEFAULT_PUB_KEY.parse().expect("Invalid default pub key"),
+            server_sec_key: DEFAULT_SEC_KEY.parse().expect("Invalid default sec key"),
+            refuse_default_keys: false,
             handlers: vec![],
             messages_to_send: None,
+            routed_messages_to_send: None,
+            extensions_to_send: None,
+            interceptors: vec![],
+            invalid_frame_policy: InvalidFramePolicy::default(),
+            invalid_frame_handler: None,
+            protocol: None,
+            min_version: 2,
+            max_version: 2,
+            supported_flags: u32::MAX,
+            server_flags: 0,
+            setup_connection_handler: None,
+            extensions: ExtensionRegistry::default(),
+            extensions_handler: None,
+            requests: None,
+            shutdown: None,
+            authorizer: None,
+            origins: Origins::default(),
         }
     }
     pub fn try_with_client(
@@ -124,7 +1029,9 @@ impl ServerBuilder {
         from_client: Receiver<Frame_>,
         to_client: Sender<Frame_>,
     ) -> Result<&mut Self, ServerBuilderError> {
-        if self.from_client.is_none() && self.to_client.is_none() {
+        if self.listener.is_some() {
+            Err(ServerBuilderError::CanNotListenAndHaveAClient)
+        } else if self.from_client.is_none() && self.to_client.is_none() {
             self.from_client = Some(from_client);
             self.to_client = Some(to_client);
             Ok(self)
@@ -136,23 +1043,75 @@ impl ServerBuilder {
         &mut self,
         stream: TcpStream,
     ) -> Result<&mut Self, ServerBuilderError> {
-        if self.from_client.is_none() && self.to_client.is_none() {
-            let auth_pub_k_as_bytes = self.server_pub_key.into_bytes();
-            let auth_prv_k_as_bytes = self.server_sec_key.into_bytes();
-            let responder = Responder::from_authority_kp(
-                &auth_pub_k_as_bytes,
-                &auth_prv_k_as_bytes,
-                std::time::Duration::from_secs(self.cert_validity),
-            )
-            .expect("invalid key pair");
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_client_stream(stream, &peer).await?;
+        self.client_address = address;
+        Ok(self)
+    }
 
-            if let Ok((receiver_from_client, send_to_client, _, _)) =
-                Connection::new::<'static, PoolMessages<'static>>(
-                    stream,
-                    HandshakeRole::Responder(responder),
-                )
-                .await
-            {
+    /// Like `try_add_client` but the frames are exchanged without the Noise handshake and
+    /// without encryption. The stream must be on the loopback interface, otherwise this fails
+    /// with `UnencryptedNotOnLoopback`.
+    pub fn try_add_client_unencrypted(
+        &mut self,
+        stream: TcpStream,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        if !is_loopback(stream.local_addr()) {
+            return Err(ServerBuilderError::UnencryptedNotOnLoopback);
+        }
+        let peer = peer_name(&stream);
+        let address = stream.peer_addr().ok();
+        self.add_client_stream_unencrypted(stream, &peer)?;
+        self.client_address = address;
+        Ok(self)
+    }
+
+    /// Like `try_add_client` but over a unix domain socket.
+    pub async fn try_add_client_unix(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        self.add_client_stream(stream, "unix socket").await
+    }
+    /// Like `try_add_client_unencrypted` but over a unix domain socket, that is always local.
+    pub fn try_add_client_unix_unencrypted(
+        &mut self,
+        stream: UnixStream,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        self.add_client_stream_unencrypted(stream, "unix socket")
+    }
+    /// Like `try_add_client` but over any stream, for example a custom tunnel. The Noise
+    /// handshake and the SV2 framing are done on top of the stream. There is no unencrypted
+    /// variant since it is not possible to tell if a stream is local.
+    pub async fn try_add_client_stream<S>(
+        &mut self,
+        stream: S,
+    ) -> Result<&mut Self, ServerBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.add_client_stream(stream, "stream").await
+    }
+    async fn add_client_stream<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ServerBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if self.listener.is_some() {
+            Err(ServerBuilderError::CanNotListenAndHaveAClient)
+        } else if self.from_client.is_none() && self.to_client.is_none() {
+            let responder = HandshakeRole::Responder(self.authority_keys()?.responder());
+            let connection = timeout(
+                self.timeouts.handshake,
+                noise_connection(stream, peer, responder),
+            )
+            .await
+            .map_err(|_| ServerBuilderError::HandshakeTimeout)?;
+            if let Some((receiver_from_client, send_to_client, _)) = connection {
                 self.from_client = Some(receiver_from_client);
                 self.to_client = Some(send_to_client);
                 Ok(self)
@@ -163,52 +1122,718 @@ impl ServerBuilder {
             Err(ServerBuilderError::CanNotHaveMoreThan1Client)
         }
     }
+    fn add_client_stream_unencrypted<S>(
+        &mut self,
+        stream: S,
+        peer: &str,
+    ) -> Result<&mut Self, ServerBuilderError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if self.listener.is_some() {
+            Err(ServerBuilderError::CanNotListenAndHaveAClient)
+        } else if self.from_client.is_none() && self.to_client.is_none() {
+            let (receiver_from_client, send_to_client, _) = plain_connection(stream, peer);
+            self.from_client = Some(receiver_from_client);
+            self.to_client = Some(send_to_client);
+            Ok(self)
+        } else {
+            Err(ServerBuilderError::CanNotHaveMoreThan1Client)
+        }
+    }
+
+    /// Accept any number of downstreams from `listener`, every downstream gets its own noise
+    /// handshake and a `ConnectionId` that routed handlers and senders can use.
+    pub fn try_with_listener(
+        &mut self,
+        listener: TcpListener,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        if self.from_client.is_some() || self.to_client.is_some() {
+            Err(ServerBuilderError::CanNotListenAndHaveAClient)
+        } else if self.listener.is_none() {
+            self.listener = Some(listener);
+            Ok(self)
+        } else {
+            Err(ServerBuilderError::CanNotHaveMoreThan1Listener)
+        }
+    }
+
+    /// Bind `addr` and accept any number of downstreams, see `try_with_listener`.
+    pub async fn listen(
+        &mut self,
+        addr: impl ToSocketAddrs,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            eprintln!("Impossible to bind listener: {e}");
+            ServerBuilderError::ImpossibleToBindListener
+        })?;
+        self.try_with_listener(listener)
+    }
+
+    /// Like `try_with_listener` but the downstreams are accepted without the Noise handshake
+    /// and without encryption. Only meant for local deployments (a sidecar, a Template Provider
+    /// client) or to debug with a packet capture, so the listener must be bound to the loopback
+    /// interface, otherwise this fails with `UnencryptedNotOnLoopback`.
+    pub fn try_with_unencrypted_listener(
+        &mut self,
+        listener: TcpListener,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        if !is_loopback(listener.local_addr()) {
+            return Err(ServerBuilderError::UnencryptedNotOnLoopback);
+        }
+        self.try_with_listener(listener)?;
+        self.unencrypted_listener = true;
+        Ok(self)
+    }
+
+    /// Bind `addr` and accept any number of downstreams without encryption, see
+    /// `try_with_unencrypted_listener`.
+    pub async fn listen_unencrypted(
+        &mut self,
+        addr: impl ToSocketAddrs,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            eprintln!("Impossible to bind listener: {e}");
+            ServerBuilderError::ImpossibleToBindListener
+        })?;
+        self.try_with_unencrypted_listener(listener)
+    }
 
-    pub fn add_handler(&mut self, message_type: u8) -> Receiver<PoolMessages<'static>> {
-        let (s, r) = channel(3);
+    /// Observe the messages of type `message_type` sent by the downstreams, every handler
+    /// registered for a message type receives its messages.
+    pub fn add_handler(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<PoolMessages<'static>> {
+        self.add_handler_with_config(message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_handler` but with the capacity of the channel and what to do when it is full,
+    /// the returned counter tells how many messages have been dropped.
+    pub fn add_handler_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<PoolMessages<'static>>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
         let channel = MessageChannel {
-            message_type,
-            expect_from: Remote::Server,
+            key: message_type.into(),
+            expect_from: Remote::Client,
             receiver: None,
-            sender: s,
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
         };
-        self.handlers.push(channel);
-        r
+        self.handlers.push(channel.into());
+        (r, dropped)
     }
+    /// Receive the messages of type `message_type` sent by the downstreams, for each of them a
+    /// reply must be sent back with the returned sender. A message type has at most one handler
+    /// with a reply, it receives the messages after the handlers without reply.
     pub fn add_handler_with_sender(
         &mut self,
-        message_type: u8,
+        message_type: impl Into<HandlerKey>,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+        ),
+        ServerBuilderError,
+    > {
+        let (r, s, _) =
+            self.add_handler_with_sender_with_config(message_type, HandlerConfig::default())?;
+        Ok((r, s))
+    }
+    /// Like `add_handler_with_sender` but with the capacity of the channels and what to do when
+    /// they are full, like `add_handler_with_config`.
+    pub fn add_handler_with_sender_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> Result<
+        (
+            Receiver<PoolMessages<'static>>,
+            Sender<PoolMessages<'static>>,
+            DropCounter,
+        ),
+        ServerBuilderError,
+    > {
+        let (s, r) = channel(config.channel_capacity());
+        let (s1, r1) = channel(config.capacity.max(1));
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Client,
+            receiver: Some(HandlerReceiver::Plain(r1)),
+            sender: HandlerSender::Plain(s),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        push_handler(&mut self.handlers, channel)?;
+        Ok((r, s1, dropped))
+    }
+    /// Like `add_handler` but the message type comes from `T` and the messages are received as
+    /// `T`, eg `add_typed_handler::<SubmitSharesExtended<'static>>()`.
+    pub fn add_typed_handler<T: TypedMessage>(&mut self) -> Receiver<T> {
+        self.add_typed_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_typed_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_typed_handler_with_config<T: TypedMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: T::MESSAGE_TYPE.into(),
+            expect_from: Remote::Client,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(Typed(s))),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Like `add_typed_handler` but every message comes with the id of the downstream that sent
+    /// it.
+    pub fn add_routed_typed_handler<T: TypedMessage>(&mut self) -> Receiver<(ConnectionId, T)> {
+        self.add_routed_typed_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_routed_typed_handler` but with the capacity of the channel and what to do when
+    /// it is full, like `add_handler_with_config`.
+    pub fn add_routed_typed_handler_with_config<T: TypedMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<(ConnectionId, T)>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: T::MESSAGE_TYPE.into(),
+            expect_from: Remote::Client,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(RoutedTyped(s))),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Like `add_handler` but every message comes in an `Inbound`, with the id, the address and
+    /// the SetupConnection of the downstream that sent it.
+    pub fn add_inbound_handler(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<Inbound> {
+        self.add_inbound_handler_with_config(message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_inbound_handler` but with the capacity of the channel and what to do when it is
+    /// full, like `add_handler_with_config`.
+    pub fn add_inbound_handler_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<Inbound>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Client,
+            receiver: None,
+            sender: HandlerSender::Typed(Arc::new(InboundSender {
+                sender: s,
+                origins: self.origins.clone(),
+            })),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Handle the messages sent by the downstreams with `handler`, a `Reply::Send` is sent back
+    /// to the downstream that sent the message. Only one `Handler` can be registered, it does not
+    /// receive the message types that have a handler with a reply.
+    pub fn add_message_handler(
+        &mut self,
+        handler: impl Handler,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        push_handler(
+            &mut self.handlers,
+            MessageHandler::new(Remote::Client, handler),
+        )?;
+        Ok(self)
+    }
+    /// Like `add_handler` but every message comes with the id of the downstream that sent it.
+    pub fn add_routed_handler(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Receiver<RoutedMessage> {
+        self.add_routed_handler_with_config(message_type, HandlerConfig::default())
+            .0
+    }
+    /// Like `add_handler_with_config` but every message comes with the id of the downstream that
+    /// sent it.
+    pub fn add_routed_handler_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> (Receiver<RoutedMessage>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Client,
+            receiver: None,
+            sender: HandlerSender::Routed(s),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        self.handlers.push(channel.into());
+        (r, dropped)
+    }
+    /// Like `add_handler_with_sender` but every message comes with the id of the downstream that
+    /// sent it, and the reply is sent to the downstream with the returned id.
+    pub fn add_routed_handler_with_sender(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+    ) -> Result<(Receiver<RoutedMessage>, Sender<RoutedMessage>), ServerBuilderError> {
+        let (r, s, _) = self
+            .add_routed_handler_with_sender_with_config(message_type, HandlerConfig::default())?;
+        Ok((r, s))
+    }
+    /// Like `add_routed_handler_with_sender` but with the capacity of the channels and what to
+    /// do when they are full, like `add_handler_with_config`.
+    pub fn add_routed_handler_with_sender_with_config(
+        &mut self,
+        message_type: impl Into<HandlerKey>,
+        config: HandlerConfig,
+    ) -> Result<(Receiver<RoutedMessage>, Sender<RoutedMessage>, DropCounter), ServerBuilderError>
+    {
+        let (s, r) = channel(config.channel_capacity());
+        let (s1, r1) = channel(config.capacity.max(1));
+        let dropped = DropCounter::default();
+        let channel = MessageChannel {
+            key: message_type.into(),
+            expect_from: Remote::Client,
+            receiver: Some(HandlerReceiver::Routed(r1)),
+            sender: HandlerSender::Routed(s),
+            overflow: Overflow::new(config, dropped.clone()),
+        };
+        push_handler(&mut self.handlers, channel)?;
+        Ok((r, s1, dropped))
+    }
+    /// Messages sent here go to every connected downstream.
+    pub fn add_message_sender(&mut self) -> Sender<PoolMessages<'static>> {
+        self.add_message_sender_with_capacity(3)
+    }
+    /// Like `add_message_sender` but with a channel that holds `capacity` messages.
+    pub fn add_message_sender_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Sender<PoolMessages<'static>> {
+        let (s, r) = channel(capacity.max(1));
+        self.messages_to_send = Some(r);
+        s
+    }
+    /// Messages sent here go to a specific downstream or to all of them.
+    pub fn add_routed_message_sender(&mut self) -> Sender<(Destination, PoolMessages<'static>)> {
+        self.add_routed_message_sender_with_capacity(3)
+    }
+    /// Like `add_routed_message_sender` but with a channel that holds `capacity` messages.
+    pub fn add_routed_message_sender_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Sender<(Destination, PoolMessages<'static>)> {
+        let (s, r) = channel(capacity.max(1));
+        self.routed_messages_to_send = Some(r);
+        s
+    }
+    /// Support the extension `extension_type`: the `RequestExtensions` of the downstreams are
+    /// answered with the registered extensions that they request.
+    pub fn add_extension(&mut self, extension_type: u16) -> &mut Self {
+        self.extensions.add(extension_type, false);
+        self
+    }
+    /// Like `add_extension` but a downstream that does not request the extension gets a
+    /// `RequestExtensions.Error` listing it as required. It is only checked when the downstream
+    /// sends a `RequestExtensions`: one that never sends it is not disconnected, use the
+    /// negotiated extensions handler to know which downstreams negotiated the extension.
+    pub fn add_required_extension(&mut self, extension_type: u16) -> &mut Self {
+        self.extensions.add(extension_type, true);
+        self
+    }
+    /// Receive the messages of type `T` of an extension sent by the downstreams, the extension of
+    /// `T` is supported like with `add_extension`.
+    pub fn add_extension_handler<T: ExtensionMessage>(&mut self) -> Receiver<T> {
+        self.add_extension_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_extension_handler` but with the capacity of the channel and what to do when it
+    /// is full, like `add_handler_with_config`.
+    pub fn add_extension_handler_with_config<T: ExtensionMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<T>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        self.extensions.add(T::EXTENSION_TYPE, false);
+        let sender = RawChannel::typed(s, Overflow::new(config, dropped.clone()));
+        self.handlers
+            .push(RawHandler::typed::<T>(Remote::Client, sender).into());
+        (r, dropped)
+    }
+    /// Like `add_extension_handler` but every message comes with the id of the downstream that
+    /// sent it.
+    pub fn add_routed_extension_handler<T: ExtensionMessage>(
+        &mut self,
+    ) -> Receiver<(ConnectionId, T)> {
+        self.add_routed_extension_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_routed_extension_handler` but with the capacity of the channel and what to do
+    /// when it is full, like `add_handler_with_config`.
+    pub fn add_routed_extension_handler_with_config<T: ExtensionMessage>(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<(ConnectionId, T)>, DropCounter) {
+        let (s, r) = channel(config.channel_capacity());
+        let dropped = DropCounter::default();
+        self.extensions.add(T::EXTENSION_TYPE, false);
+        let sender = RawChannel::routed(s, Overflow::new(config, dropped.clone()));
+        self.handlers
+            .push(RawHandler::typed::<T>(Remote::Client, sender).into());
+        (r, dropped)
+    }
+    /// Frames sent here go to a specific downstream or to all of them, eg
+    /// `(Destination::Broadcast, RawFrame::from_message(&message))` for an extension message.
+    /// They do not go through the outbound interceptors. A payload of 2^24 bytes or more makes
+    /// `start` fail with `ServerError::PayloadTooLong`.
+    pub fn add_extension_sender(&mut self) -> Sender<(Destination, RawFrame)> {
+        self.add_extension_sender_with_capacity(3)
+    }
+    /// Like `add_extension_sender` but with a channel that holds `capacity` frames.
+    pub fn add_extension_sender_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Sender<(Destination, RawFrame)> {
+        let (s, r) = channel(capacity.max(1));
+        self.extensions_to_send = Some(r);
+        s
+    }
+    /// Receive the extensions negotiated with every downstream that sends a `RequestExtensions`.
+    pub fn add_negotiated_extensions_handler(&mut self) -> Receiver<(ConnectionId, Vec<u16>)> {
+        self.add_negotiated_extensions_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_negotiated_extensions_handler` but with the capacity of the channel and what to
+    /// do when it is full, like `add_handler_with_config`.
+    pub fn add_negotiated_extensions_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<(ConnectionId, Vec<u16>)>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.extensions_handler = Some(observer);
+        (r, dropped)
+    }
+    /// Every message sent with the message senders goes through the interceptor before reaching
+    /// the downstreams: the interceptor receives it and must send back the message to send in its
+    /// place. Interceptors are applied in the order they are added.
+    pub fn add_outbound_interceptor(
+        &mut self,
     ) -> (
         Receiver<PoolMessages<'static>>,
         Sender<PoolMessages<'static>>,
     ) {
-        let (s, r) = channel(3);
-        let (s1, r1) = channel(3);
-        let channel = MessageChannel {
-            message_type,
-            expect_from: Remote::Server,
-            receiver: Some(r1),
+        self.add_outbound_interceptor_with_capacity(3)
+    }
+    /// Like `add_outbound_interceptor` but with channels that hold `capacity` messages.
+    pub fn add_outbound_interceptor_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> (
+        Receiver<PoolMessages<'static>>,
+        Sender<PoolMessages<'static>>,
+    ) {
+        let (s, r) = channel(capacity.max(1));
+        let (s1, r1) = channel(capacity.max(1));
+        self.interceptors.push(Interceptor {
             sender: s,
-        };
-        self.handlers.push(channel);
+            receiver: r1,
+        });
         (r, s1)
     }
-    pub fn add_message_sender(&mut self) -> Sender<PoolMessages<'static>> {
-        let (s, r) = channel(3);
-        self.messages_to_send = Some(r);
-        s
+    /// Only accept downstreams that open the connection for `protocol`, by default any protocol
+    /// is accepted.
+    pub fn with_protocol(&mut self, protocol: Protocol) -> &mut Self {
+        self.protocol = Some(protocol);
+        self
+    }
+    /// Versions of the protocol supported by the server, the default is 2 to 2.
+    pub fn with_versions(
+        &mut self,
+        min_version: u16,
+        max_version: u16,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        if min_version > max_version {
+            return Err(ServerBuilderError::InvalidVersionRange);
+        }
+        self.min_version = min_version;
+        self.max_version = max_version;
+        Ok(self)
+    }
+    /// Flags that downstreams can set in `SetupConnection`, a downstream setting any other flag
+    /// is rejected with `unsupported-feature-flags`. By default every flag is accepted.
+    pub fn with_supported_flags(&mut self, flags: u32) -> &mut Self {
+        self.supported_flags = flags;
+        self
+    }
+    /// Flags sent to downstreams in `SetupConnection.Success`, the default is 0.
+    pub fn with_server_flags(&mut self, flags: u32) -> &mut Self {
+        self.server_flags = flags;
+        self
+    }
+    /// A `ServerRequester` to send requests to the downstreams and wait for their response. The
+    /// `request_id` sequence is shared by all the requesters, responses are not sent to the
+    /// handlers.
+    pub fn add_requester(&mut self, timeout: Duration) -> ServerRequester {
+        self.add_requester_with_capacity(timeout, 3)
+    }
+    /// Like `add_requester` but the channel of the requests holds `capacity` of them. The
+    /// requesters share that channel, it is created by the first one added.
+    pub fn add_requester_with_capacity(
+        &mut self,
+        timeout: Duration,
+        capacity: usize,
+    ) -> ServerRequester {
+        let (sender, _) = self
+            .requests
+            .get_or_insert_with(|| channel(capacity.max(1)));
+        ServerRequester::new(sender.clone(), timeout)
+    }
+    /// A `ShutdownHandle` to stop the server, the goodbye messages for `Remote::Client` are sent
+    /// to every downstream. A listening server stops accepting new downstreams.
+    pub fn add_shutdown_handle(&mut self) -> ShutdownHandle {
+        self.shutdown
+            .get_or_insert_with(ShutdownHandle::new)
+            .clone()
+    }
+    /// Receive the `SetupConnection` of every accepted downstream, before any other message from
+    /// that downstream reaches the handlers.
+    pub fn add_setup_connection_handler(
+        &mut self,
+    ) -> Receiver<(ConnectionId, SetupConnection<'static>)> {
+        self.add_setup_connection_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_setup_connection_handler` but with the capacity of the channel and what to do
+    /// when it is full, like `add_handler_with_config`.
+    pub fn add_setup_connection_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (
+        Receiver<(ConnectionId, SetupConnection<'static>)>,
+        DropCounter,
+    ) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.setup_connection_handler = Some(observer);
+        (r, dropped)
+    }
+    /// Decide with `authorizer` which downstreams can complete the SetupConnection and open
+    /// channels, see `Authorizer`. By default every downstream is accepted.
+    pub fn with_authorizer(&mut self, authorizer: impl Authorizer) -> &mut Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+    /// The identities given to the downstreams by the authorizer, to know on behalf of whom a
+    /// message received by a routed handler has been sent. Inbound handlers receive them with
+    /// the messages.
+    pub fn add_identities(&mut self) -> Identities {
+        self.origins.identities().clone()
+    }
+    /// What to do when a downstream sends a frame that can not be decoded, the default is to
+    /// close the connection with that downstream.
+    pub fn with_invalid_frame_policy(&mut self, policy: InvalidFramePolicy) -> &mut Self {
+        self.invalid_frame_policy = policy;
+        self
+    }
+    /// Receive an `InvalidFrame` every time a downstream sends a frame that can not be decoded.
+    pub fn add_invalid_frame_handler(&mut self) -> Receiver<InvalidFrame> {
+        self.add_invalid_frame_handler_with_config(HandlerConfig::default())
+            .0
+    }
+    /// Like `add_invalid_frame_handler` but with the capacity of the channel and what to do when
+    /// it is full, like `add_handler_with_config`.
+    pub fn add_invalid_frame_handler_with_config(
+        &mut self,
+        config: HandlerConfig,
+    ) -> (Receiver<InvalidFrame>, DropCounter) {
+        let (observer, r, dropped) = Observer::new(config);
+        self.invalid_frame_handler = Some(observer);
+        (r, dropped)
+    }
+    /// Validity in seconds of the certificate sent to the downstreams during the noise
+    /// handshake. Like the keys it must be set before `try_add_client`.
+    pub fn override_cert_validity(&mut self, cert_validity: u64) -> &mut Self {
+        self.cert_validity = cert_validity;
+        self
+    }
+    pub fn override_server_pub_key(
+        &mut self,
+        pub_key: String,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        self.server_pub_key = pub_key.parse()?;
+        Ok(self)
+    }
+    pub fn override_server_sec_key(
+        &mut self,
+        sec_key: String,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        self.server_sec_key = sec_key.parse()?;
+        Ok(self)
+    }
+    /// Read the authority keys from a key file, see the `keys` module for the format.
+    pub fn with_keys_from_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        let key_pair = AuthorityKeyPair::read(path)?;
+        self.server_pub_key = key_pair.public_key;
+        self.server_sec_key = key_pair.secret_key;
+        Ok(self)
+    }
+    /// Read the base58 encoded authority keys from the environment variables `pub_key_var` and
+    /// `sec_key_var`.
+    pub fn with_keys_from_env(
+        &mut self,
+        pub_key_var: &str,
+        sec_key_var: &str,
+    ) -> Result<&mut Self, ServerBuilderError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| ServerBuilderError::MissingKeyEnvVar(name.to_string()))
+        };
+        self.server_pub_key = var(pub_key_var)?.parse()?;
+        self.server_sec_key = var(sec_key_var)?.parse()?;
+        Ok(self)
+    }
+    /// Maximum time for a downstream to complete the Noise handshake, a downstream accepted by
+    /// the listener that does not complete it in time is dropped. Default is 10 seconds.
+    pub fn with_handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+    /// Maximum time for a downstream to send the SetupConnection after the handshake. Without a
+    /// listener the server fails with `SetupTimeout`, with a listener the downstream is dropped.
+    /// Default is 10 seconds.
+    pub fn with_setup_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.setup = timeout;
+        self
+    }
+    /// Close the connection with a downstream when nothing is received from it for longer than
+    /// `timeout`. Without a listener the server fails with `IdleTimeout`. By default there is no
+    /// idle timeout.
+    pub fn with_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.idle = Some(timeout);
+        self
     }
-    pub fn try_build(self) -> Result<Server, ServerBuilderError> {
-        if let (Some(from_client), Some(to_client)) = (self.from_client, self.to_client) {
-            Ok(Server {
+    /// The default keys are the public keys of the stratum examples, anyone can use them to
+    /// impersonate the server. After this call `try_add_client` and `try_build` with a listener
+    /// fail with `DefaultKeysNotAllowed` if the keys have not been overridden, production
+    /// servers should call it.
+    pub fn refuse_default_keys(&mut self) -> &mut Self {
+        self.refuse_default_keys = true;
+        self
+    }
+    fn authority_keys(&self) -> Result<AuthorityKeys, ServerBuilderError> {
+        let default_sec_key: Secp256k1SecretKey =
+            DEFAULT_SEC_KEY.parse().expect("Invalid default sec key");
+        if self.server_sec_key.into_bytes() == default_sec_key.into_bytes() {
+            if self.refuse_default_keys {
+                eprintln!("Refusing to use the default authority keys, override them");
+                return Err(ServerBuilderError::DefaultKeysNotAllowed);
+            }
+            eprintln!("Using the default authority keys, anyone can impersonate the server");
+        }
+        if Secp256k1PublicKey::from(self.server_sec_key).into_bytes()
+            != self.server_pub_key.into_bytes()
+        {
+            return Err(ServerBuilderError::KeyPairMismatch);
+        }
+        Ok(AuthorityKeys {
+            pub_key: self.server_pub_key,
+            sec_key: self.server_sec_key,
+            cert_validity: self.cert_validity,
+        })
+    }
+    pub fn try_build(mut self) -> Result<Server, ServerBuilderError> {
+        let keys = match self.listener {
+            Some(_) if !self.unencrypted_listener => Some(self.authority_keys()?),
+            _ => None,
+        };
+        let downstream = match (self.from_client, self.to_client, self.listener) {
+            (Some(from_client), Some(to_client), None) => Downstream::Single {
                 from_client,
                 to_client,
-                handlers: self.handlers,
-                messages_to_send: self.messages_to_send,
-            })
-        } else {
-            Err(ServerBuilderError::IncompleteBuilder)
+                address: self.client_address,
+            },
+            (None, None, Some(listener)) => Downstream::Listener { listener, keys },
+            _ => return Err(ServerBuilderError::IncompleteBuilder),
+        };
+        if !self.extensions.is_empty() {
+            self.handlers.push(
+                ExtensionNegotiator {
+                    extensions: self.extensions,
+                    observer: self.extensions_handler,
+                }
+                .into(),
+            );
         }
+        Ok(Server {
+            downstream,
+            handlers: Dispatcher::new(Remote::Client, self.handlers),
+            messages_to_send: self.messages_to_send,
+            routed_messages_to_send: self.routed_messages_to_send,
+            extensions_to_send: self.extensions_to_send,
+            interceptors: self.interceptors,
+            requests: self.requests.map(|(_, receiver)| receiver),
+            shutdown: ShutdownSignal::from_handle(self.shutdown),
+            invalid_frames: InvalidFrames {
+                policy: self.invalid_frame_policy,
+                observer: self.invalid_frame_handler,
+            },
+            setup_connection: Arc::new(SetupConnectionNegotiator {
+                protocol: self.protocol,
+                min_version: self.min_version,
+                max_version: self.max_version,
+                supported_flags: self.supported_flags,
+                server_flags: self.server_flags,
+                observer: self.setup_connection_handler,
+                timeout: self.timeouts.setup,
+                authorizer: self.authorizer,
+            }),
+            timeouts: self.timeouts,
+            origins: self.origins,
+        })
+    }
+}
+
+impl From<MessageChannelError> for ServerError {
+    fn from(value: MessageChannelError) -> Self {
+        match value {
+            MessageChannelError::InvalidFrame(e) => Self::InvalidFrame(e),
+            MessageChannelError::HandlerDropped(mt) => Self::HandlerDropped(mt),
+            MessageChannelError::HandlerOverflow(mt) => Self::HandlerOverflow(mt),
+        }
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<KeysError> for ServerBuilderError {
+    fn from(value: KeysError) -> Self {
+        Self::KeyFileError(value)
     }
 }
 
@@ -217,3 +1842,124 @@ impl From<KeyUtilsError> for ServerBuilderError {
         Self::KeyError(value)
     }
 }
+
+impl From<OwnerConflict> for ServerBuilderError {
+    fn from(value: OwnerConflict) -> Self {
+        match value {
+            OwnerConflict::Key(key) => Self::OwnerAlreadyRegistered(key),
+            OwnerConflict::MessageHandler => Self::MessageHandlerAlreadyRegistered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiator(protocol: Option<Protocol>) -> SetupConnectionNegotiator {
+        SetupConnectionNegotiator {
+            protocol,
+            min_version: 2,
+            max_version: 3,
+            supported_flags: 0b0110,
+            server_flags: 0b1000,
+            observer: None,
+            timeout: Duration::from_secs(1),
+            authorizer: None,
+        }
+    }
+
+    fn setup_connection(
+        min_version: u16,
+        max_version: u16,
+        flags: u32,
+    ) -> SetupConnection<'static> {
+        SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version,
+            max_version,
+            flags,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        }
+    }
+
+    /// The flags and the error code of a refused SetupConnection.
+    fn refusal(
+        result: Result<SetupConnectionSuccess, SetupConnectionError<'static>>,
+    ) -> (u32, String) {
+        let error = result.expect_err("The SetupConnection should be refused");
+        (
+            error.flags,
+            String::from_utf8(error.error_code.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn accepted_setup_connection() {
+        let success = negotiator(Some(Protocol::MiningProtocol))
+            .check(&setup_connection(1, 2, 0b0100))
+            .unwrap();
+        assert_eq!(success.used_version, 2);
+        assert_eq!(success.flags, 0b1000);
+    }
+
+    #[test]
+    fn unsupported_protocol() {
+        assert_eq!(
+            refusal(
+                negotiator(Some(Protocol::JobDeclarationProtocol))
+                    .check(&setup_connection(2, 2, 0))
+            ),
+            (0, "unsupported-protocol".to_string())
+        );
+        assert!(negotiator(None).check(&setup_connection(2, 2, 0)).is_ok());
+    }
+
+    #[test]
+    fn protocol_version_mismatch() {
+        for (min, max) in [(1, 1), (4, 5)] {
+            assert_eq!(
+                refusal(negotiator(None).check(&setup_connection(min, max, 0))),
+                (0, "protocol-version-mismatch".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn default_keys_are_only_refused_on_request() {
+        let mut builder = ServerBuilder::new();
+        assert!(builder.authority_keys().is_ok());
+        builder.refuse_default_keys();
+        assert!(matches!(
+            builder.authority_keys(),
+            Err(ServerBuilderError::DefaultKeysNotAllowed)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transient_accept_errors() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_transient_accept_error(&Error::from(
+            ErrorKind::ConnectionAborted
+        )));
+        assert!(is_transient_accept_error(&Error::from_raw_os_error(EMFILE)));
+        assert!(is_transient_accept_error(&Error::from_raw_os_error(ENFILE)));
+        assert!(!is_transient_accept_error(&Error::from(
+            ErrorKind::InvalidInput
+        )));
+    }
+
+    #[test]
+    fn unsupported_feature_flags() {
+        assert_eq!(
+            refusal(negotiator(None).check(&setup_connection(2, 2, 0b1011))),
+            (0b1001, "unsupported-feature-flags".to_string())
+        );
+    }
+}
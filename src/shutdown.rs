@@ -0,0 +1,91 @@
+use roles_logic_sv2::parsers::PoolMessages;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::message_channel::Remote;
+
+type Goodbye = Vec<(Remote, PoolMessages<'static>)>;
+
+/// Stop a `Client`, a `Server` or a `Proxy`. When the shutdown is requested the frames already
+/// received are dispatched to the handlers, the messages already queued in the message senders
+/// are sent, then the goodbye messages are sent and the connections are closed. After that
+/// `start` returns `Ok(())`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<Option<Goodbye>>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = watch::channel(None);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown_with(vec![]);
+    }
+
+    /// Shutdown sending `messages` before closing the connections, like a `CloseChannel` or a
+    /// `Reconnect`. Each message goes to the given peer: `Remote::Server` for the upstream and
+    /// `Remote::Client` for the downstreams, a `Server` sends them to every downstream.
+    pub fn shutdown_with(&self, messages: Vec<(Remote, PoolMessages<'static>)>) {
+        self.sender.send_if_modified(|goodbye| {
+            if goodbye.is_none() {
+                *goodbye = Some(messages);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub(crate) fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: Some(self.sender.subscribe()),
+        }
+    }
+}
+
+/// The receiving side of a `ShutdownHandle`, a signal without handle is never triggered.
+#[derive(Clone, Default)]
+pub(crate) struct ShutdownSignal {
+    receiver: Option<watch::Receiver<Option<Goodbye>>>,
+}
+
+impl ShutdownSignal {
+    pub(crate) fn from_handle(handle: Option<ShutdownHandle>) -> Self {
+        handle.map(|h| h.signal()).unwrap_or_default()
+    }
+
+    /// Return when the shutdown is requested.
+    pub(crate) async fn requested(&self) {
+        let Some(mut receiver) = self.receiver.clone() else {
+            return std::future::pending().await;
+        };
+        loop {
+            if receiver.borrow_and_update().is_some() {
+                return;
+            }
+            if receiver.changed().await.is_err() {
+                // Every handle has been dropped without requesting the shutdown
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    /// The goodbye messages to send to `to`.
+    pub(crate) fn goodbye(&self, to: Remote) -> Vec<PoolMessages<'static>> {
+        let Some(receiver) = &self.receiver else {
+            return vec![];
+        };
+        receiver
+            .borrow()
+            .iter()
+            .flatten()
+            .filter(|(remote, _)| *remote == to)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,372 @@
+use demand_easy_sv2::roles_logic_sv2::{
+    common_messages_sv2::Protocol,
+    mining_sv2::{
+        OpenMiningChannelError, OpenStandardMiningChannel, OpenStandardMiningChannelSuccess,
+        SubmitSharesStandard,
+    },
+    parsers::{Mining, PoolMessages},
+};
+use demand_easy_sv2::*;
+use std::{future::Future, time::Duration};
+
+/// Fail the test instead of hanging when `future` does not complete.
+async fn within<T>(future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_secs(5), future)
+        .await
+        .expect("Timed out")
+}
+
+fn client(endpoint: Endpoint) -> ClientBuilder {
+    let mut builder = ClientBuilder::new();
+    builder
+        .with_protocol(Protocol::MiningProtocol)
+        .unwrap()
+        .try_with_server(endpoint.receiver, endpoint.sender)
+        .unwrap();
+    builder
+}
+
+fn server(endpoint: Endpoint) -> ServerBuilder {
+    let mut builder = ServerBuilder::new();
+    builder
+        .try_with_client(endpoint.receiver, endpoint.sender)
+        .unwrap();
+    builder
+}
+
+fn share(channel_id: u32) -> PoolMessages<'static> {
+    PoolMessages::Mining(Mining::SubmitSharesStandard(SubmitSharesStandard {
+        channel_id,
+        sequence_number: 0,
+        job_id: 0,
+        nonce: 0,
+        ntime: 0,
+        version: 0,
+    }))
+}
+
+fn open_standard_mining_channel(user_identity: &str) -> PoolMessages<'static> {
+    PoolMessages::Mining(Mining::OpenStandardMiningChannel(
+        OpenStandardMiningChannel {
+            request_id: 0.into(),
+            user_identity: user_identity.to_string().try_into().unwrap(),
+            nominal_hash_rate: 1.0,
+            max_target: [0xff; 32].into(),
+        },
+    ))
+}
+
+fn request_id(message: &PoolMessages) -> u32 {
+    match message {
+        PoolMessages::Mining(Mining::OpenStandardMiningChannel(m)) => m.request_id.as_u32(),
+        PoolMessages::Mining(Mining::OpenMiningChannelError(m)) => m.request_id,
+        _ => panic!("Unexpected message"),
+    }
+}
+
+#[tokio::test]
+async fn setup_connection_then_messages() {
+    let (client_end, server_end) = pair();
+    let mut server = server(server_end);
+    let mut setups = server.add_setup_connection_handler();
+    let mut shares = server.add_typed_handler::<SubmitSharesStandard>();
+    tokio::spawn(server.try_build().unwrap().start());
+
+    let mut client = client(client_end);
+    let mut successes = client.add_setup_connection_handler();
+    let sender = client.add_message_sender();
+    tokio::spawn(client.try_build().unwrap().start());
+
+    let (connection, setup) = within(setups.recv()).await.unwrap();
+    assert_eq!(connection, 0);
+    assert_eq!(setup.protocol, Protocol::MiningProtocol);
+    assert_eq!(within(successes.recv()).await.unwrap().used_version, 2);
+    sender.send(share(3)).await.unwrap();
+    assert_eq!(within(shares.recv()).await.unwrap().channel_id, 3);
+}
+
+#[tokio::test]
+async fn noise_pair_exchanges_messages() {
+    let (client_end, server_end) = noise_pair().await;
+    let mut server = server(server_end);
+    let mut shares = server.add_typed_handler::<SubmitSharesStandard>();
+    tokio::spawn(server.try_build().unwrap().start());
+
+    let mut client = client(client_end);
+    let sender = client.add_message_sender();
+    tokio::spawn(client.try_build().unwrap().start());
+
+    sender.send(share(4)).await.unwrap();
+    assert_eq!(within(shares.recv()).await.unwrap().channel_id, 4);
+}
+
+#[tokio::test]
+async fn requester_receives_the_response() {
+    let (client_end, server_end) = pair();
+    let mut server = server(server_end);
+    let (mut requests, replies) = server
+        .add_handler_with_sender(const_sv2::MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL)
+        .unwrap();
+    tokio::spawn(server.try_build().unwrap().start());
+    tokio::spawn(async move {
+        while let Some(request) = requests.recv().await {
+            let error = OpenMiningChannelError {
+                request_id: request_id(&request),
+                error_code: "unknown-user".to_string().try_into().unwrap(),
+            };
+            let error = PoolMessages::Mining(Mining::OpenMiningChannelError(error));
+            if replies.send(error).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut client = client(client_end);
+    let requester = client.add_requester(Duration::from_secs(5));
+    let mut errors = client.add_handler(const_sv2::MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR);
+    tokio::spawn(client.try_build().unwrap().start());
+
+    let response = within(requester.request(open_standard_mining_channel("alice")))
+        .await
+        .unwrap();
+    // The ids of the requests do not collide with the ids chosen by the application
+    assert!(request_id(&response) >= 0x8000_0000);
+    assert!(errors.try_recv().is_err());
+    assert_eq!(
+        requester.request(share(1)).await.err(),
+        Some(RequestError::NotARequest)
+    );
+}
+
+/// Gives the identity `"connection"` to the SetupConnection and the user identity of the open
+/// channel messages to their channel.
+struct ByUser;
+
+impl Authorizer for ByUser {
+    async fn on_setup_connection(
+        &self,
+        _peer: Peer,
+        _message: roles_logic_sv2::common_messages_sv2::SetupConnection<'static>,
+    ) -> Authorization {
+        Authorization::AcceptAs("connection".to_string())
+    }
+
+    async fn on_open_standard_mining_channel(
+        &self,
+        peer: Peer,
+        message: OpenStandardMiningChannel<'static>,
+    ) -> Authorization {
+        assert_eq!(peer.identity.as_deref(), Some("connection"));
+        let user = String::from_utf8(message.user_identity.to_vec()).unwrap();
+        match user.as_str() {
+            "mallory" => Authorization::Reject("unknown-user".to_string()),
+            _ => Authorization::AcceptAs(user),
+        }
+    }
+}
+
+#[tokio::test]
+async fn identities_come_with_the_inbound_messages() {
+    let (client_end, server_end) = pair();
+    let mut server = server(server_end);
+    server.with_authorizer(ByUser);
+    let (mut opens, replies) = server
+        .add_handler_with_sender(const_sv2::MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL)
+        .unwrap();
+    let mut shares = server.add_inbound_handler(const_sv2::MESSAGE_TYPE_SUBMIT_SHARES_STANDARD);
+    let identities = server.add_identities();
+    tokio::spawn(server.try_build().unwrap().start());
+    // Channel 7 for the first open channel message, 8 for the second
+    tokio::spawn(async move {
+        for channel_id in 7.. {
+            let Some(open) = opens.recv().await else {
+                break;
+            };
+            let success = OpenStandardMiningChannelSuccess {
+                request_id: request_id(&open).into(),
+                channel_id,
+                target: [0xff; 32].into(),
+                extranonce_prefix: vec![0; 4].try_into().unwrap(),
+                group_channel_id: 1,
+            };
+            let success = PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(success));
+            if replies.send(success).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut client = client(client_end);
+    let requester = client.add_requester(Duration::from_secs(5));
+    let sender = client.add_message_sender();
+    tokio::spawn(client.try_build().unwrap().start());
+
+    for user in ["alice", "bob"] {
+        let response = within(requester.request(open_standard_mining_channel(user))).await;
+        assert!(matches!(
+            response,
+            Ok(PoolMessages::Mining(
+                Mining::OpenStandardMiningChannelSuccess(_)
+            ))
+        ));
+    }
+    let refused = within(requester.request(open_standard_mining_channel("mallory"))).await;
+    assert!(matches!(
+        refused,
+        Ok(PoolMessages::Mining(Mining::OpenMiningChannelError(_)))
+    ));
+
+    for channel_id in [7, 8, 9] {
+        sender.send(share(channel_id)).await.unwrap();
+    }
+    let mut received = vec![];
+    for _ in 0..3 {
+        let inbound = within(shares.recv()).await.unwrap();
+        assert_eq!(inbound.conn_id, 0);
+        assert_eq!(inbound.used_version, Some(2));
+        assert!(inbound.setup.is_some());
+        received.push(inbound.identity);
+    }
+    assert_eq!(
+        received,
+        [
+            Some("alice".to_string()),
+            Some("bob".to_string()),
+            Some("connection".to_string())
+        ]
+    );
+    assert_eq!(identities.get(0).as_deref(), Some("connection"));
+    assert_eq!(identities.get_channel(0, 8).as_deref(), Some("bob"));
+}
+
+#[tokio::test]
+async fn optional_extension_without_server_support() {
+    let (client_end, server_end) = pair();
+    let mut server = server(server_end);
+    let mut shares = server.add_typed_handler::<SubmitSharesStandard>();
+    tokio::spawn(server.try_build().unwrap().start());
+
+    let mut client = client(client_end);
+    client.add_extension(5);
+    let sender = client.add_message_sender();
+    tokio::spawn(client.try_build().unwrap().start());
+
+    sender.send(share(1)).await.unwrap();
+    assert_eq!(within(shares.recv()).await.unwrap().channel_id, 1);
+}
+
+#[tokio::test]
+async fn extensions_are_negotiated() {
+    let (client_end, server_end) = pair();
+    let mut server = server(server_end);
+    server.add_extension(5);
+    let mut server_negotiated = server.add_negotiated_extensions_handler();
+    tokio::spawn(server.try_build().unwrap().start());
+
+    let mut client = client(client_end);
+    client.add_extension(5).add_extension(6);
+    let mut client_negotiated = client.add_negotiated_extensions_handler();
+    tokio::spawn(client.try_build().unwrap().start());
+
+    assert_eq!(
+        within(server_negotiated.recv()).await.unwrap(),
+        (0, vec![5])
+    );
+    assert_eq!(within(client_negotiated.recv()).await.unwrap(), [5]);
+}
+
+#[tokio::test]
+async fn required_extension_rejected_by_the_server() {
+    let (client_end, server_end) = pair();
+    tokio::spawn(server(server_end).try_build().unwrap().start());
+    let (client_end_two, server_end_two) = pair();
+    let mut server_two = server(server_end_two);
+    server_two.add_extension(6);
+    tokio::spawn(server_two.try_build().unwrap().start());
+
+    // The server does not answer the negotiation
+    let mut waiting = client(client_end);
+    waiting
+        .add_required_extension(5)
+        .with_setup_timeout(Duration::from_millis(200));
+    let result = within(waiting.try_build().unwrap().start()).await;
+    assert_eq!(result, Err(ClientError::SetupTimeout));
+
+    let mut rejected = client(client_end_two);
+    rejected.add_required_extension(5);
+    let result = within(rejected.try_build().unwrap().start()).await;
+    assert_eq!(
+        result,
+        Err(ClientError::ExtensionsRejected {
+            unsupported: vec![5],
+            required: vec![],
+        })
+    );
+}
+
+#[tokio::test]
+async fn oversized_extension_payload_stops_the_client() {
+    let (client_end, server_end) = pair();
+    tokio::spawn(server(server_end).try_build().unwrap().start());
+
+    let mut client = client(client_end);
+    let extensions = client.add_extension_sender();
+    let client = tokio::spawn(client.try_build().unwrap().start());
+
+    let frame = RawFrame {
+        extension_type: 5,
+        channel_msg: false,
+        message_type: 1,
+        payload: vec![0; 1 << 24],
+    };
+    extensions.send(frame).await.unwrap();
+    assert_eq!(
+        within(client).await.unwrap(),
+        Err(ClientError::PayloadTooLong(1))
+    );
+}
+
+#[tokio::test]
+async fn shutdown_while_connecting_to_an_upstream() {
+    // The upstream accepts the TCP connection but never answers the handshake
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = ClientBuilder::new();
+    client
+        .with_protocol(Protocol::MiningProtocol)
+        .unwrap()
+        .add_upstream(listener.local_addr().unwrap().to_string(), None)
+        .unwrap();
+    let shutdown = client.add_shutdown_handle();
+    let client = tokio::spawn(client.try_build().unwrap().start());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.shutdown();
+    assert_eq!(within(client).await.unwrap(), Ok(()));
+}
+
+#[tokio::test]
+async fn proxy_setup_timeout() {
+    fn proxy(client_end: Endpoint, server_end: Endpoint) -> Proxy {
+        let mut builder = ProxyBuilder::new();
+        builder
+            .try_with_client(client_end.receiver, client_end.sender)
+            .unwrap()
+            .try_with_server(server_end.receiver, server_end.sender)
+            .unwrap()
+            .with_setup_timeout(Duration::from_millis(200));
+        builder.try_build().unwrap()
+    }
+
+    // The downstream does not send the SetupConnection
+    let (_silent_client, proxy_client_end) = pair();
+    let (proxy_server_end, _server_end) = pair();
+    let result = within(proxy(proxy_client_end, proxy_server_end).start()).await;
+    assert_eq!(result, Err(ProxyError::SetupTimeout));
+
+    // The upstream does not answer it
+    let (client_end, proxy_client_end) = pair();
+    let (proxy_server_end, _silent_server) = pair();
+    tokio::spawn(client(client_end).try_build().unwrap().start());
+    let result = within(proxy(proxy_client_end, proxy_server_end).start()).await;
+    assert_eq!(result, Err(ProxyError::SetupTimeout));
+}